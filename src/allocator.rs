@@ -1,6 +1,7 @@
 use alloc::alloc::{GlobalAlloc, Layout};
-use bump::BumpAllocator;
+use fixed_size_block::FixedSizeBlockAllocator;
 use core::ptr::null_mut;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use linked_list_allocator::LockedHeap;
 use x86_64::{
   structures::paging::{
@@ -10,15 +11,32 @@ use x86_64::{
 };
 
 pub mod bump;
+pub mod fixed_size_block;
 
 #[global_allocator]
-static ALLOCATOR: Locked<BumpAllocator> = Locked::new(BumpAllocator::new());
+static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
+// static ALLOCATOR: Locked<bump::BumpAllocator> = Locked::new(bump::BumpAllocator::new());
 // static ALLOCATOR: LockedHeap = LockedHeap::empty();
 // static ALLOCATOR: Dummy = Dummy;
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
 pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
 
+// number of power of two buckets in the allocation size histogram
+// bucket i counts allocations of size (2^(i-1), 2^i], the last bucket also holds anything larger
+pub const SIZE_HISTOGRAM_BUCKETS: usize = 16;
+
+// running count of allocations per size bucket
+const EMPTY_BUCKET: AtomicUsize = AtomicUsize::new(0);
+static SIZE_HISTOGRAM: [AtomicUsize; SIZE_HISTOGRAM_BUCKETS] = [EMPTY_BUCKET; SIZE_HISTOGRAM_BUCKETS];
+
+// number of buckets in the allocation alignment histogram
+// bucket i counts allocations aligned to 2^i, the last bucket also holds anything larger
+pub const ALIGN_HISTOGRAM_BUCKETS: usize = 16;
+
+// running count of allocations per alignment bucket
+static ALIGN_HISTOGRAM: [AtomicUsize; ALIGN_HISTOGRAM_BUCKETS] = [EMPTY_BUCKET; ALIGN_HISTOGRAM_BUCKETS];
+
 /**
  * init_heap maps a range of virtual address to physical addresses to be used for the heap
  */
@@ -52,6 +70,69 @@ pub fn init_heap(
   Ok(())
 }
 
+/**
+ * size_histogram returns the number of allocations made so far in each power of two size bucket
+ * e.g. an 8 byte allocation lands in bucket 3, a 20 byte allocation in bucket 5 (32 bytes)
+ */
+pub fn size_histogram() -> [usize; SIZE_HISTOGRAM_BUCKETS] {
+  let mut histogram = [0; SIZE_HISTOGRAM_BUCKETS];
+  for (count, bucket) in histogram.iter_mut().zip(SIZE_HISTOGRAM.iter()) {
+    *count = bucket.load(Ordering::Relaxed);
+  }
+  histogram
+}
+
+/**
+ * size_bucket returns the histogram bucket for an allocation of the given size
+ */
+fn size_bucket(size: usize) -> usize {
+  let bucket = size.next_power_of_two().trailing_zeros() as usize;
+  bucket.min(SIZE_HISTOGRAM_BUCKETS - 1)
+}
+
+/**
+ * align_histogram returns the number of allocations made so far with each power of two alignment
+ * e.g. an allocation aligned to 8 bytes lands in bucket 3, one aligned to 256 bytes in bucket 8
+ */
+pub fn align_histogram() -> [usize; ALIGN_HISTOGRAM_BUCKETS] {
+  let mut histogram = [0; ALIGN_HISTOGRAM_BUCKETS];
+  for (count, bucket) in histogram.iter_mut().zip(ALIGN_HISTOGRAM.iter()) {
+    *count = bucket.load(Ordering::Relaxed);
+  }
+  histogram
+}
+
+/**
+ * align_bucket returns the histogram bucket for an allocation with the given alignment
+ * Layout guarantees the alignment is a power of two
+ */
+fn align_bucket(align: usize) -> usize {
+  let bucket = align.trailing_zeros() as usize;
+  bucket.min(ALIGN_HISTOGRAM_BUCKETS - 1)
+}
+
+/**
+ * record_allocation adds the layout to the size and alignment histograms
+ * it only touches atomics so it is safe to call from inside an allocator
+ */
+fn record_allocation(layout: &Layout) {
+  SIZE_HISTOGRAM[size_bucket(layout.size())].fetch_add(1, Ordering::Relaxed);
+  ALIGN_HISTOGRAM[align_bucket(layout.align())].fetch_add(1, Ordering::Relaxed);
+}
+
+// debug builds overwrite freed memory with this byte so use-after-free bugs read obvious garbage
+pub const POISON_BYTE: u8 = 0xde;
+
+/**
+ * poison fills a freed region with POISON_BYTE in debug builds, release builds skip it
+ * unsafe because the caller must own the size bytes at ptr
+ */
+unsafe fn poison(ptr: *mut u8, size: usize) {
+  if cfg!(debug_assertions) {
+    core::ptr::write_bytes(ptr, POISON_BYTE, size);
+  }
+}
+
 /**
  * align addr upwards to alignment align
  * if addr is not a multiple of the alignment, make it so
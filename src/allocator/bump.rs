@@ -1,4 +1,4 @@
-use super::{align_up, Locked};
+use super::{align_up, record_allocation, Locked};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr;
 
@@ -55,6 +55,7 @@ unsafe impl GlobalAlloc for Locked<BumpAllocator> {
       // move next and allocations, return alloc_start as a addr pointer
       bump.next = alloc_end;
       bump.allocations += 1;
+      record_allocation(&layout);
       alloc_start as *mut u8
     }
   }
@@ -0,0 +1,117 @@
+use super::{poison, record_allocation, Locked};
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{mem, ptr, ptr::NonNull};
+
+// the block sizes to use
+// each size must be a power of 2 because it is also used as the block alignment
+const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+// ListNode is a free block, it is stored inside the block it describes
+struct ListNode {
+  next: Option<&'static mut ListNode>,
+}
+
+/**
+ * represent an allocator that hands out fixed size blocks
+ * freed blocks go back onto a free list for their size and are reused,
+ * requests too large for any block size go to a linked list allocator
+ */
+pub struct FixedSizeBlockAllocator {
+  list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()], // one free list per block size
+  fallback_allocator: linked_list_allocator::Heap,                // for large allocations
+}
+
+impl FixedSizeBlockAllocator {
+  /**
+   * create an empty FixedSizeBlockAllocator
+   */
+  pub const fn new() -> Self {
+    const EMPTY: Option<&'static mut ListNode> = None;
+    FixedSizeBlockAllocator {
+      list_heads: [EMPTY; BLOCK_SIZES.len()],
+      fallback_allocator: linked_list_allocator::Heap::empty(),
+    }
+  }
+
+  /**
+   * initialize the allocator with the given heap bounds
+   * unsafe because the caller must ensure the heap_start and heap_size are valid and unused
+   */
+  pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+    self.fallback_allocator.init(heap_start, heap_size);
+  }
+
+  /**
+   * allocate from the fallback allocator
+   */
+  fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+    match self.fallback_allocator.allocate_first_fit(layout) {
+      Ok(ptr) => ptr.as_ptr(),
+      Err(_) => ptr::null_mut(),
+    }
+  }
+}
+
+/**
+ * choose a block size for the given layout
+ * returns the index into BLOCK_SIZES, or None if the layout is too large for any block
+ */
+fn list_index(layout: &Layout) -> Option<usize> {
+  let required_block_size = layout.size().max(layout.align());
+  BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+}
+
+unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
+  unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    let mut allocator = self.lock(); // get safe mutable reference
+    record_allocation(&layout);
+
+    match list_index(&layout) {
+      Some(index) => {
+        match allocator.list_heads[index].take() {
+          // reuse a freed block by popping it off the list
+          Some(node) => {
+            allocator.list_heads[index] = node.next.take();
+            node as *mut ListNode as *mut u8
+          }
+          // no free block of this size, carve a new one out of the fallback heap
+          // the block is aligned to its own size, so it can hold any layout that maps to it
+          None => {
+            let block_size = BLOCK_SIZES[index];
+            let block_align = block_size;
+            let layout = Layout::from_size_align(block_size, block_align).unwrap();
+            allocator.fallback_alloc(layout)
+          }
+        }
+      }
+      None => allocator.fallback_alloc(layout),
+    }
+  }
+
+  unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    let mut allocator = self.lock(); // get safe mutable reference
+
+    match list_index(&layout) {
+      Some(index) => {
+        // the list node below overwrites the start of the poisoned block
+        poison(ptr, BLOCK_SIZES[index]);
+
+        // push the freed block onto the front of its list
+        let new_node = ListNode {
+          next: allocator.list_heads[index].take(),
+        };
+        // every block is large and aligned enough to hold a ListNode
+        assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+        assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+        let new_node_ptr = ptr as *mut ListNode;
+        new_node_ptr.write(new_node);
+        allocator.list_heads[index] = Some(&mut *new_node_ptr);
+      }
+      None => {
+        poison(ptr, layout.size());
+        let ptr = NonNull::new(ptr).unwrap();
+        allocator.fallback_allocator.deallocate(ptr, layout);
+      }
+    }
+  }
+}
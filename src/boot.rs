@@ -0,0 +1,369 @@
+// boot.rs brings the kernel up as an explicit, ordered list of phases.
+// each phase runs after the ones before it, so ordering dependencies (e.g. the heap needing
+// the frame allocator from the memory phase) are visible in one place instead of implied by
+// the order of calls in kernel_main.
+
+use crate::memory::{self, BootInfoFrameAllocator};
+use crate::{allocator, gdt, interrupts, println, serial_println, time};
+use bootloader::BootInfo;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::structures::paging::OffsetPageTable;
+use x86_64::VirtAddr;
+
+// Phase is a single named step of the boot sequence
+pub struct Phase<C> {
+  pub name: &'static str,
+  pub post_code: u8, // written to the POST port when the phase starts
+  pub run: fn(&mut C) -> Result<(), &'static str>,
+}
+
+// BootError reports which phase failed and why
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootError {
+  pub phase: &'static str,
+  pub reason: &'static str,
+}
+
+impl fmt::Display for BootError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "boot phase '{}' failed: {}", self.phase, self.reason)
+  }
+}
+
+// BootContext carries the state phases hand to each other
+pub struct BootContext {
+  boot_info: Option<&'static BootInfo>, // None when booted without the bootloader's info, see CPU_PHASES
+  mapper: Option<OffsetPageTable<'static>>,
+  frame_allocator: Option<BootInfoFrameAllocator>,
+}
+
+impl BootContext {
+  /**
+   * new creates the context the first phase starts with
+   * without boot info only the CPU_PHASES can run, the memory phase fails
+   */
+  pub fn new(boot_info: Option<&'static BootInfo>) -> Self {
+    BootContext {
+      boot_info,
+      mapper: None,
+      frame_allocator: None,
+    }
+  }
+}
+
+// KernelMemory is the page table mapper and frame allocator the memory phase set up
+pub struct KernelMemory {
+  pub mapper: OffsetPageTable<'static>,
+  pub frame_allocator: BootInfoFrameAllocator,
+  pub phys_mem_offset: VirtAddr, // where the bootloader mapped all of physical memory
+}
+
+// kept once boot finishes so pages can still be mapped, None until then
+// there must only ever be one frame allocator, a second one would hand out frames already in use
+pub static MEMORY: Mutex<Option<KernelMemory>> = Mutex::new(None);
+
+// the kernel boot sequence, in the order it must run
+pub static PHASES: [Phase<BootContext>; 6] = [
+  Phase { name: "gdt", post_code: 0x10, run: init_gdt },
+  Phase { name: "idt", post_code: 0x20, run: init_idt },
+  Phase { name: "pic", post_code: 0x30, run: init_pic },
+  Phase { name: "memory", post_code: 0x40, run: init_memory },
+  Phase { name: "heap", post_code: 0x50, run: init_heap },
+  Phase { name: "devices", post_code: 0x60, run: init_devices },
+];
+
+// the leading phases of PHASES that only set up the CPU and need no boot info (gdt, idt, pic)
+pub const CPU_PHASES: usize = 3;
+
+// time stamp counter value when the kernel started booting, 0 until recorded
+static BOOT_START_TSC: AtomicU64 = AtomicU64::new(0);
+
+/**
+ * mark_start records the moment boot started, only the first call has an effect
+ * call it as early as possible, run and init call it as well in case nothing did before
+ */
+pub fn mark_start() {
+  let _ = BOOT_START_TSC.compare_exchange(0, time::rdtsc(), Ordering::Relaxed, Ordering::Relaxed);
+}
+
+/**
+ * elapsed_cycles returns the time stamp counter cycles since mark_start
+ */
+pub fn elapsed_cycles() -> u64 {
+  time::rdtsc().saturating_sub(BOOT_START_TSC.load(Ordering::Relaxed))
+}
+
+/**
+ * elapsed_ms returns the milliseconds since mark_start
+ * None until the timer has calibrated the time stamp counter (see time::tsc_per_ms),
+ * report elapsed_cycles instead until then
+ */
+pub fn elapsed_ms() -> Option<u64> {
+  let cycles = elapsed_cycles();
+  time::tsc_per_ms().map(|per_ms| cycles / per_ms)
+}
+
+/**
+ * run boots the kernel by running every phase in PHASES
+ * afterwards the mapper and frame allocator live on in MEMORY
+ */
+pub fn run(boot_info: &'static BootInfo) -> Result<(), BootError> {
+  mark_start();
+  let mut context = BootContext::new(Some(boot_info));
+  run_phases(&PHASES, &mut context)?;
+  if let (Some(mapper), Some(frame_allocator)) = (context.mapper, context.frame_allocator) {
+    *MEMORY.lock() = Some(KernelMemory {
+      mapper,
+      frame_allocator,
+      phys_mem_offset: VirtAddr::new(boot_info.physical_memory_offset),
+    });
+  }
+  fire_ready_callbacks();
+  Ok(())
+}
+
+/**
+ * with_memory calls f with the kernel's mapper and frame allocator, e.g. to map a page after boot
+ * returns None if boot::run hasn't set them up
+ */
+pub fn with_memory<R>(f: impl FnOnce(&mut KernelMemory) -> R) -> Option<R> {
+  x86_64::instructions::interrupts::without_interrupts(|| MEMORY.lock().as_mut().map(f))
+}
+
+// the most callbacks on_ready can hold
+pub const MAX_READY_CALLBACKS: usize = 8;
+
+// ReadyCallbacks holds the functions waiting for boot to finish
+struct ReadyCallbacks {
+  callbacks: [Option<fn()>; MAX_READY_CALLBACKS],
+  count: usize,
+  fired: bool, // boot has finished, new callbacks run right away
+}
+
+static READY_CALLBACKS: Mutex<ReadyCallbacks> = Mutex::new(ReadyCallbacks {
+  callbacks: [None; MAX_READY_CALLBACKS],
+  count: 0,
+  fired: false,
+});
+
+/**
+ * on_ready registers a function to run once boot::run has brought everything up, heap included
+ * callbacks run in the order they were registered; registering after boot runs the callback immediately
+ * panics if more than MAX_READY_CALLBACKS are waiting
+ */
+pub fn on_ready(callback: fn()) {
+  let run_now = {
+    let mut ready = READY_CALLBACKS.lock();
+    if !ready.fired {
+      assert!(ready.count < MAX_READY_CALLBACKS, "too many boot ready callbacks");
+      let index = ready.count;
+      ready.callbacks[index] = Some(callback);
+      ready.count += 1;
+    }
+    ready.fired
+  };
+  if run_now {
+    callback();
+  }
+}
+
+/**
+ * fire_ready_callbacks runs and forgets every waiting callback
+ * the lock is released first so that a callback can call on_ready itself
+ */
+fn fire_ready_callbacks() {
+  let callbacks = {
+    let mut ready = READY_CALLBACKS.lock();
+    ready.fired = true;
+    ready.count = 0;
+    core::mem::replace(&mut ready.callbacks, [None; MAX_READY_CALLBACKS])
+  };
+  for callback in callbacks.iter().flatten() {
+    callback();
+  }
+}
+
+/**
+ * run_phases runs each phase in order, stopping at the first one that fails
+ */
+pub fn run_phases<C>(phases: &[Phase<C>], context: &mut C) -> Result<(), BootError> {
+  for phase in phases {
+    post(phase.post_code);
+    serial_println!("[boot] {:#04x} {}", phase.post_code, phase.name);
+
+    if let Err(reason) = (phase.run)(context) {
+      let error = BootError {
+        phase: phase.name,
+        reason,
+      };
+      println!("[boot] {}", error);
+      serial_println!("[boot] {}", error);
+      return Err(error);
+    }
+  }
+  Ok(())
+}
+
+/**
+ * post writes a POST code to port 0x80 so boot progress is visible to a POST card or debugger
+ */
+fn post(code: u8) {
+  use x86_64::instructions::port::Port;
+
+  let mut port = Port::new(0x80);
+  unsafe { port.write(code) };
+}
+
+fn init_gdt(_context: &mut BootContext) -> Result<(), &'static str> {
+  gdt::init();
+  Ok(())
+}
+
+fn init_idt(_context: &mut BootContext) -> Result<(), &'static str> {
+  interrupts::init_idt();
+  Ok(())
+}
+
+fn init_pic(_context: &mut BootContext) -> Result<(), &'static str> {
+  unsafe { interrupts::PICS.lock().initialize() };
+  Ok(())
+}
+
+fn init_memory(context: &mut BootContext) -> Result<(), &'static str> {
+  let boot_info = context.boot_info.ok_or("no boot info")?;
+  let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+  context.mapper = Some(unsafe { memory::init(phys_mem_offset) });
+  context.frame_allocator = Some(unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) });
+  Ok(())
+}
+
+fn init_heap(context: &mut BootContext) -> Result<(), &'static str> {
+  let mapper = context.mapper.as_mut().ok_or("memory phase has not run")?;
+  let frame_allocator = context
+    .frame_allocator
+    .as_mut()
+    .ok_or("memory phase has not run")?;
+  allocator::init_heap(mapper, frame_allocator).map_err(|_| "mapping the heap failed")
+}
+
+fn init_devices(_context: &mut BootContext) -> Result<(), &'static str> {
+  #[cfg(feature = "clock")]
+  crate::clock::enable();
+
+  // devices may start raising interrupts once the CPU accepts them
+  x86_64::instructions::interrupts::enable();
+  Ok(())
+}
+
+#[test_case]
+fn test_elapsed_ms() {
+  time::sleep_ticks(3); // at least ~165 ms have passed since boot started, and calibration is done
+  let elapsed = elapsed_ms().expect("time stamp counter not calibrated");
+  assert!(elapsed > 0);
+  assert!(elapsed < 60_000);
+  assert!(elapsed_cycles() > 0);
+}
+
+#[test_case]
+fn test_on_ready_runs_once_in_order() {
+  use alloc::boxed::Box;
+  use core::sync::atomic::AtomicUsize;
+
+  // each callback appends its id to CALLS, one decimal digit per call
+  static CALLS: AtomicUsize = AtomicUsize::new(0);
+  fn first() {
+    let value = Box::new(1); // the heap is up by the time callbacks run
+    CALLS.store(CALLS.load(Ordering::Relaxed) * 10 + *value, Ordering::Relaxed);
+  }
+  fn second() {
+    CALLS.store(CALLS.load(Ordering::Relaxed) * 10 + 2, Ordering::Relaxed);
+  }
+
+  // boot has already finished in the test kernel, pretend it hasn't
+  READY_CALLBACKS.lock().fired = false;
+  on_ready(first);
+  on_ready(second);
+  assert_eq!(CALLS.load(Ordering::Relaxed), 0);
+
+  fire_ready_callbacks();
+  assert_eq!(CALLS.load(Ordering::Relaxed), 12);
+  fire_ready_callbacks(); // nothing is left to run
+  assert_eq!(CALLS.load(Ordering::Relaxed), 12);
+
+  on_ready(second); // after boot callbacks run straight away
+  assert_eq!(CALLS.load(Ordering::Relaxed), 122);
+}
+
+#[test_case]
+fn test_memory_kept_after_boot() {
+  use x86_64::structures::paging::{FrameAllocator, PageTableFlags};
+
+  let addr = 0x_2222_0000_0000;
+  let mapped = with_memory(|kernel| {
+    let frame = kernel.frame_allocator.allocate_frame().expect("out of frames");
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    unsafe { memory::create_mapping(addr, frame, flags, &mut kernel.mapper, &mut kernel.frame_allocator) }
+  });
+  assert!(matches!(mapped, Some(Ok(()))));
+
+  let ptr = addr as *mut u64;
+  unsafe {
+    ptr.write_volatile(0xb007);
+    assert_eq!(ptr.read_volatile(), 0xb007);
+  }
+}
+
+#[test_case]
+fn test_cpu_phases_need_no_boot_info() {
+  let names: [&str; CPU_PHASES] = [PHASES[0].name, PHASES[1].name, PHASES[2].name];
+  assert_eq!(names, ["gdt", "idt", "pic"]);
+
+  // the first phase after them is the one that needs boot info
+  let mut context = BootContext::new(None);
+  assert_eq!((PHASES[CPU_PHASES].run)(&mut context), Err("no boot info"));
+}
+
+#[cfg(test)]
+struct TestContext {
+  order: [u8; 3],
+  count: usize,
+}
+
+#[cfg(test)]
+fn record(context: &mut TestContext, id: u8) -> Result<(), &'static str> {
+  context.order[context.count] = id;
+  context.count += 1;
+  Ok(())
+}
+
+#[cfg(test)]
+static TEST_PHASES: [Phase<TestContext>; 3] = [
+  Phase { name: "first", post_code: 0x01, run: |c| record(c, 1) },
+  Phase { name: "second", post_code: 0x02, run: |c| record(c, 2) },
+  Phase { name: "third", post_code: 0x03, run: |c| record(c, 3) },
+];
+
+#[test_case]
+fn test_phases_run_in_order() {
+  let mut context = TestContext { order: [0; 3], count: 0 };
+
+  assert_eq!(run_phases(&TEST_PHASES, &mut context), Ok(()));
+  assert_eq!(context.order, [1, 2, 3]);
+}
+
+#[test_case]
+fn test_failing_phase_aborts_boot() {
+  let phases = [
+    Phase { name: "first", post_code: 0x01, run: TEST_PHASES[0].run },
+    Phase { name: "broken", post_code: 0x02, run: |_| Err("broken on purpose") },
+    Phase { name: "third", post_code: 0x03, run: TEST_PHASES[2].run },
+  ];
+  let mut context = TestContext { order: [0; 3], count: 0 };
+
+  let error = run_phases(&phases, &mut context).unwrap_err();
+  assert_eq!(error.phase, "broken");
+  assert_eq!(error.reason, "broken on purpose");
+  assert_eq!(context.order, [1, 0, 0]); // the third phase never ran
+}
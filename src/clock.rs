@@ -0,0 +1,118 @@
+// clock.rs paints the time of day in the top right corner of the screen.
+// the timer only flags that the clock may need repainting; the RTC is read and the clock painted
+// later by update, outside interrupt context, and only when the seconds have changed.
+
+use crate::{rtc, vga_buffer};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+// the clock takes up the last 8 columns of the top row, e.g. 13:37:00
+const CLOCK_WIDTH: usize = 8;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+// set by the timer, cleared once update has checked the RTC
+static UPDATE_DUE: AtomicBool = AtomicBool::new(false);
+// the seconds value last painted, NOT_PAINTED forces the next update to paint
+const NOT_PAINTED: u8 = 0xff;
+static LAST_SECONDS: AtomicU8 = AtomicU8::new(NOT_PAINTED);
+
+/**
+ * enable starts painting the clock
+ */
+pub fn enable() {
+  LAST_SECONDS.store(NOT_PAINTED, Ordering::Relaxed);
+  ENABLED.store(true, Ordering::Relaxed);
+}
+
+/**
+ * disable stops painting the clock, whatever was painted last stays on screen until overwritten
+ */
+pub fn disable() {
+  ENABLED.store(false, Ordering::Relaxed);
+}
+
+/**
+ * on_tick flags the clock for an update if it is enabled, called from the timer interrupt
+ * it doesn't touch the RTC, reading it can mean waiting out an RTC update
+ */
+pub(crate) fn on_tick() {
+  if ENABLED.load(Ordering::Relaxed) {
+    UPDATE_DUE.store(true, Ordering::Relaxed);
+  }
+}
+
+/**
+ * update reads the RTC and repaints the clock if the timer flagged it and the seconds changed
+ * called outside interrupt context whenever the CPU wakes up, see time::wait_for_interrupt
+ */
+pub fn update() {
+  if !ENABLED.load(Ordering::Relaxed) || !UPDATE_DUE.swap(false, Ordering::Relaxed) {
+    return;
+  }
+  match rtc::try_read_time() {
+    Some(time) => {
+      if seconds_changed(time) {
+        vga_buffer::write_at(0, vga_buffer::BUFFER_WIDTH - CLOCK_WIDTH, &format(time));
+      }
+    }
+    None => UPDATE_DUE.store(true, Ordering::Relaxed), // the RTC is updating, try again next time
+  }
+}
+
+/**
+ * seconds_changed records time as painted and tells whether its seconds differ from the last paint
+ */
+fn seconds_changed(time: rtc::RtcTime) -> bool {
+  LAST_SECONDS.swap(time.seconds, Ordering::Relaxed) != time.seconds
+}
+
+/**
+ * format renders a time as HH:MM:SS
+ */
+fn format(time: rtc::RtcTime) -> [u8; CLOCK_WIDTH] {
+  let digit = |value: u8, place: u8| b'0' + (value / place) % 10;
+  [
+    digit(time.hours, 10),
+    digit(time.hours, 1),
+    b':',
+    digit(time.minutes, 10),
+    digit(time.minutes, 1),
+    b':',
+    digit(time.seconds, 10),
+    digit(time.seconds, 1),
+  ]
+}
+
+#[test_case]
+fn test_format() {
+  let text = format(rtc::RtcTime { hours: 9, minutes: 5, seconds: 42 });
+  assert_eq!(&text, b"09:05:42");
+}
+
+#[test_case]
+fn test_repaints_once_per_second() {
+  let time = |seconds| rtc::RtcTime { hours: 12, minutes: 0, seconds };
+
+  LAST_SECONDS.store(NOT_PAINTED, Ordering::Relaxed);
+  assert!(seconds_changed(time(1)));
+  assert!(!seconds_changed(time(1))); // another tick in the same second
+  assert!(seconds_changed(time(2)));
+}
+
+#[test_case]
+#[cfg(not(feature = "headless"))]
+fn test_clock_paints_top_right() {
+  enable();
+  crate::time::sleep_ticks(2);
+  disable();
+
+  let screen = vga_buffer::screen_to_string();
+  let top_row = screen.split('\n').next().unwrap();
+  let clock = &top_row.as_bytes()[top_row.len() - CLOCK_WIDTH..];
+  for (i, &c) in clock.iter().enumerate() {
+    if i == 2 || i == 5 {
+      assert_eq!(c, b':');
+    } else {
+      assert!(c.is_ascii_digit());
+    }
+  }
+}
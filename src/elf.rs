@@ -0,0 +1,339 @@
+// elf.rs parses 64-bit ELF executables so that user programs can be loaded later on.
+// only the file header and the program headers are read, section headers are ignored.
+
+use crate::memory::is_canonical;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::ptr;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1; // little endian
+const EM_X86_64: u16 = 0x3e;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const PT_LOAD: u32 = 1;
+
+// segment permission bits from p_flags
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+// ElfError describes why an image was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+  TooShort,      // the image ends before a header it should contain
+  BadMagic,      // the image doesn't start with \x7fELF
+  NotElf64,      // the image is a 32-bit ELF
+  NotLittleEndian,
+  WrongMachine(u16), // the image isn't built for x86-64
+  BadProgramHeader,  // the program header table has an unexpected entry size
+  BadSegment,        // a segment's file size is larger than its memory size
+  InvalidAddress(u64), // a segment or the entry point isn't at a canonical address
+  FrameAllocationFailed,
+  MapFailed, // a segment overlaps a page that is already mapped
+}
+
+// Segment is a PT_LOAD program header, a piece of the image that must be loaded into memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+  pub vaddr: u64,  // virtual address the segment is loaded at
+  pub offset: u64, // offset of the segment's data in the image
+  pub filesz: u64, // number of bytes in the image
+  pub memsz: u64,  // number of bytes in memory, the rest past filesz is zeroed
+  pub flags: u32,  // PF_R, PF_W and PF_X bits
+}
+
+// ElfInfo is the result of parsing an image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ElfInfo {
+  pub entry: u64,
+  pub segments: Vec<Segment>,
+}
+
+/**
+ * parse validates the ELF header of image and collects its entry point and loadable segments
+ */
+pub fn parse(image: &[u8]) -> Result<ElfInfo, ElfError> {
+  let ident = image.get(..HEADER_SIZE).ok_or(ElfError::TooShort)?;
+  if ident[..4] != ELF_MAGIC {
+    return Err(ElfError::BadMagic);
+  }
+  if ident[4] != ELFCLASS64 {
+    return Err(ElfError::NotElf64);
+  }
+  if ident[5] != ELFDATA2LSB {
+    return Err(ElfError::NotLittleEndian);
+  }
+  let machine = read_u16(image, 18)?;
+  if machine != EM_X86_64 {
+    return Err(ElfError::WrongMachine(machine));
+  }
+
+  let entry = read_u64(image, 24)?;
+  let phoff = read_u64(image, 32)? as usize;
+  let phentsize = read_u16(image, 54)? as usize;
+  let phnum = read_u16(image, 56)? as usize;
+  if phnum > 0 && phentsize != PROGRAM_HEADER_SIZE {
+    return Err(ElfError::BadProgramHeader);
+  }
+
+  // keep only the PT_LOAD entries of the program header table
+  let mut segments = Vec::new();
+  for i in 0..phnum {
+    // a header offset that overflows can't be inside the image either
+    let header = i
+      .checked_mul(phentsize)
+      .and_then(|table_offset| phoff.checked_add(table_offset))
+      .ok_or(ElfError::TooShort)?;
+    if read_u32(image, header)? != PT_LOAD {
+      continue;
+    }
+    segments.push(Segment {
+      flags: read_u32(image, field(header, 4)?)?,
+      offset: read_u64(image, field(header, 8)?)?,
+      vaddr: read_u64(image, field(header, 16)?)?,
+      filesz: read_u64(image, field(header, 32)?)?,
+      memsz: read_u64(image, field(header, 40)?)?,
+    });
+  }
+
+  Ok(ElfInfo { entry, segments })
+}
+
+/**
+ * load maps every PT_LOAD segment of image into the address space of mapper and returns the entry point
+ * each segment gets fresh frames, its file bytes are copied in and the rest up to memsz is zeroed
+ * the frames are written through the physical memory mapping at physical_memory_offset, so the
+ * address space doesn't need to be the active one and read-only segments can still be filled
+ */
+pub fn load(
+  image: &[u8],
+  mapper: &mut impl Mapper<Size4KiB>,
+  frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+  physical_memory_offset: VirtAddr,
+) -> Result<VirtAddr, ElfError> {
+  let info = parse(image)?;
+  if !is_canonical(info.entry) {
+    return Err(ElfError::InvalidAddress(info.entry));
+  }
+  for segment in &info.segments {
+    load_segment(image, segment, mapper, frame_allocator, physical_memory_offset)?;
+  }
+  Ok(VirtAddr::new(info.entry))
+}
+
+/**
+ * load_segment maps and fills the pages of a single segment
+ */
+fn load_segment(
+  image: &[u8],
+  segment: &Segment,
+  mapper: &mut impl Mapper<Size4KiB>,
+  frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+  physical_memory_offset: VirtAddr,
+) -> Result<(), ElfError> {
+  if segment.memsz == 0 {
+    return Ok(());
+  }
+  if segment.filesz > segment.memsz {
+    return Err(ElfError::BadSegment);
+  }
+  let data_end = segment.offset.checked_add(segment.filesz).ok_or(ElfError::TooShort)?;
+  let data = image
+    .get(segment.offset as usize..data_end as usize)
+    .ok_or(ElfError::TooShort)?;
+  let (last, file_end) = segment_bounds(segment)?;
+
+  let flags = segment_flags(segment.flags);
+  let first_page = Page::<Size4KiB>::containing_address(VirtAddr::new(segment.vaddr));
+  let last_page = Page::<Size4KiB>::containing_address(VirtAddr::new(last));
+  for page in Page::range_inclusive(first_page, last_page) {
+    let frame = frame_allocator
+      .allocate_frame()
+      .ok_or(ElfError::FrameAllocationFailed)?;
+
+    // zero the frame first, which also takes care of the memsz - filesz tail
+    let frame_ptr: *mut u8 = (physical_memory_offset + frame.start_address().as_u64()).as_mut_ptr();
+    unsafe { ptr::write_bytes(frame_ptr, 0, 4096) };
+
+    // copy the part of the file data that lands in this page
+    let page_start = page.start_address().as_u64();
+    let copy_start = page_start.max(segment.vaddr);
+    let copy_end = (page_start + 4096).min(file_end); // segment_bounds made sure this can't overflow
+    if copy_start < copy_end {
+      let bytes = &data[(copy_start - segment.vaddr) as usize..(copy_end - segment.vaddr) as usize];
+      unsafe {
+        let dest = frame_ptr.add((copy_start - page_start) as usize);
+        ptr::copy_nonoverlapping(bytes.as_ptr(), dest, bytes.len());
+      }
+    }
+
+    unsafe { mapper.map_to(page, frame, flags, frame_allocator) }
+      .map_err(|_| ElfError::MapFailed)?
+      .flush();
+  }
+  Ok(())
+}
+
+/**
+ * segment_bounds returns the last byte of the segment in memory and the end of its file data
+ * segments that aren't canonical or whose pages would run past the top of the address space are rejected,
+ * so page ends (page start + 4096) up to the last page can't overflow
+ */
+fn segment_bounds(segment: &Segment) -> Result<(u64, u64), ElfError> {
+  let invalid = ElfError::InvalidAddress(segment.vaddr);
+  let last = segment.vaddr.checked_add(segment.memsz - 1).ok_or(invalid)?;
+  if !is_canonical(segment.vaddr) || !is_canonical(last) {
+    return Err(invalid);
+  }
+  let last_page_start = last & !0xfff;
+  last_page_start.checked_add(4096).ok_or(invalid)?;
+  let file_end = segment.vaddr.checked_add(segment.filesz).ok_or(invalid)?;
+  Ok((last, file_end))
+}
+
+/**
+ * segment_flags converts ELF segment permissions into page table flags
+ * segments belong to user programs, so their pages are user accessible
+ */
+fn segment_flags(flags: u32) -> PageTableFlags {
+  let mut page_flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+  if flags & PF_W != 0 {
+    page_flags |= PageTableFlags::WRITABLE;
+  }
+  if flags & PF_X == 0 {
+    page_flags |= PageTableFlags::NO_EXECUTE;
+  }
+  page_flags
+}
+
+// offset of a field within a header, failing like a cut short image if it overflows
+fn field(header: usize, offset: usize) -> Result<usize, ElfError> {
+  header.checked_add(offset).ok_or(ElfError::TooShort)
+}
+
+// the len bytes at offset, failing instead of panicking or wrapping when they aren't all in the image
+fn bytes_at(image: &[u8], offset: usize, len: usize) -> Result<&[u8], ElfError> {
+  let end = offset.checked_add(len).ok_or(ElfError::TooShort)?;
+  image.get(offset..end).ok_or(ElfError::TooShort)
+}
+
+// little endian readers that fail instead of panicking when the image is cut short
+fn read_u16(image: &[u8], offset: usize) -> Result<u16, ElfError> {
+  let bytes = bytes_at(image, offset, 2)?;
+  Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u32(image: &[u8], offset: usize) -> Result<u32, ElfError> {
+  let bytes = bytes_at(image, offset, 4)?;
+  Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u64(image: &[u8], offset: usize) -> Result<u64, ElfError> {
+  let bytes = bytes_at(image, offset, 8)?;
+  Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+// a minimal x86-64 executable: the file header followed by a single PT_LOAD program header
+#[cfg(test)]
+const TEST_IMAGE: [u8; HEADER_SIZE + PROGRAM_HEADER_SIZE] = [
+  // e_ident: magic, 64-bit, little endian, version 1, System V ABI
+  0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+  0x02, 0x00, // e_type: executable
+  0x3e, 0x00, // e_machine: x86-64
+  0x01, 0x00, 0x00, 0x00, // e_version
+  0x00, 0x10, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, // e_entry: 0x401000
+  0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // e_phoff: 64
+  0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // e_shoff
+  0x00, 0x00, 0x00, 0x00, // e_flags
+  0x40, 0x00, // e_ehsize
+  0x38, 0x00, // e_phentsize: 56
+  0x01, 0x00, // e_phnum
+  0x40, 0x00, // e_shentsize
+  0x00, 0x00, // e_shnum
+  0x00, 0x00, // e_shstrndx
+  // program header
+  0x01, 0x00, 0x00, 0x00, // p_type: PT_LOAD
+  0x05, 0x00, 0x00, 0x00, // p_flags: R + X
+  0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // p_offset
+  0x00, 0x10, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, // p_vaddr: 0x401000
+  0x00, 0x10, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, // p_paddr
+  0x78, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // p_filesz: 120
+  0x00, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // p_memsz: 0x2000
+  0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // p_align
+];
+
+#[test_case]
+fn test_parse_entry_and_segment() {
+  let info = parse(&TEST_IMAGE).expect("parsing failed");
+  assert_eq!(info.entry, 0x40_1000);
+  assert_eq!(
+    info.segments,
+    [Segment {
+      vaddr: 0x40_1000,
+      offset: 0,
+      filesz: 120,
+      memsz: 0x2000,
+      flags: PF_R | PF_X,
+    }]
+  );
+}
+
+#[test_case]
+fn test_segment_flags() {
+  let code = segment_flags(PF_R | PF_X);
+  assert!(!code.contains(PageTableFlags::WRITABLE));
+  assert!(!code.contains(PageTableFlags::NO_EXECUTE));
+
+  let data = segment_flags(PF_R | PF_W);
+  assert!(data.contains(PageTableFlags::WRITABLE));
+  assert!(data.contains(PageTableFlags::NO_EXECUTE));
+}
+
+#[test_case]
+fn test_parse_rejects_bad_images() {
+  let mut image = TEST_IMAGE;
+  image[0] = 0;
+  assert_eq!(parse(&image), Err(ElfError::BadMagic));
+
+  let mut image = TEST_IMAGE;
+  image[18] = 0x03; // EM_386
+  assert_eq!(parse(&image), Err(ElfError::WrongMachine(3)));
+
+  assert_eq!(parse(&TEST_IMAGE[..HEADER_SIZE + 8]), Err(ElfError::TooShort));
+}
+
+#[test_case]
+fn test_parse_rejects_overflowing_offsets() {
+  // e_phoff so large that adding to it wraps around
+  let mut image = TEST_IMAGE;
+  for byte in image[32..40].iter_mut() {
+    *byte = 0xff;
+  }
+  assert_eq!(parse(&image), Err(ElfError::TooShort));
+
+  assert_eq!(read_u64(&TEST_IMAGE, usize::MAX - 3), Err(ElfError::TooShort));
+  assert_eq!(field(usize::MAX, 4), Err(ElfError::TooShort));
+}
+
+#[test_case]
+fn test_segment_bounds_near_top_of_address_space() {
+  let segment = |vaddr: u64, filesz: u64, memsz: u64| Segment {
+    vaddr,
+    offset: 0,
+    filesz,
+    memsz,
+    flags: PF_R,
+  };
+
+  assert_eq!(segment_bounds(&segment(0x40_1000, 120, 0x2000)), Ok((0x40_2fff, 0x40_1078)));
+  // the last page of the address space, its end wraps around to 0
+  let top = 0xffff_ffff_ffff_f000;
+  assert_eq!(segment_bounds(&segment(top, 16, 0x1000)), Err(ElfError::InvalidAddress(top)));
+  // memsz runs past the end of the address space
+  assert_eq!(segment_bounds(&segment(top, 16, 0x2000)), Err(ElfError::InvalidAddress(top)));
+}
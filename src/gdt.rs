@@ -1,3 +1,5 @@
+use crate::println;
+use core::sync::atomic::{AtomicBool, Ordering};
 use lazy_static::lazy_static;
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
@@ -9,6 +11,7 @@ use x86_64::VirtAddr;
 pub fn init() {
   use x86_64::instructions::segmentation::set_cs;
   use x86_64::instructions::tables::load_tss;
+  write_stack_canaries();
   GDT.0.load();
   // Tell the CPU to use the loaded code selector and tss selector
   // this is unsafe because it's possible to load invalid selectors
@@ -20,26 +23,94 @@ pub fn init() {
 
 // describes where in the IST the stack pointer goes
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
+
+// sizes of the IST stacks, raise these if a handler's call chain gets deeper
+pub const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 5;
+pub const PAGE_FAULT_STACK_SIZE: usize = 4096 * 5;
+
+// the IST stacks are filled with this byte before first use, so the untouched bottom of a stack
+// shows how close a handler came to running off the end of it
+pub const STACK_CANARY: u8 = 0xca;
+// warn once less than this many bytes of an IST stack have never been used
+const STACK_WARN_HEADROOM: usize = 1024;
+
+// zero initialized so the stacks stay in .bss, init writes the canaries
+// a page fault inside the page fault handler starts again at the top of PAGE_FAULT_STACK, on top of
+// the outer handler's frame; the handler detects this and halts rather than ever returning (see interrupts.rs)
+static mut DOUBLE_FAULT_STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
+static mut PAGE_FAULT_STACK: [u8; PAGE_FAULT_STACK_SIZE] = [0; PAGE_FAULT_STACK_SIZE];
+static CANARIES_WRITTEN: AtomicBool = AtomicBool::new(false);
+
+// fill the IST stacks with STACK_CANARY, only the first call does anything
+// so a stack that is already in use is never overwritten
+fn write_stack_canaries() {
+  if CANARIES_WRITTEN.swap(true, Ordering::SeqCst) {
+    return;
+  }
+  unsafe {
+    DOUBLE_FAULT_STACK.iter_mut().for_each(|byte| *byte = STACK_CANARY);
+    PAGE_FAULT_STACK.iter_mut().for_each(|byte| *byte = STACK_CANARY);
+  }
+}
 
 // lazily initialize the Task State Segment (TSS)
 // TSS holds two stack tables
 lazy_static! {
   static ref TSS: TaskStateSegment = {
     let mut tss = TaskStateSegment::new();
-    // write to the 0th IST a stack
-    // use stack_end because stacks grow from high -> low in x86
-    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-      const STACK_SIZE: usize = 4096 * 5;
-      static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-      let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-      let stack_end = stack_start + STACK_SIZE;
-      stack_end
-    };
+    // write a stack to each IST entry that is in use
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack_end(unsafe { &DOUBLE_FAULT_STACK });
+    tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = stack_end(unsafe { &PAGE_FAULT_STACK });
     tss
   };
 }
 
+// use the end of the stack because stacks grow from high -> low in x86
+fn stack_end(stack: &'static [u8]) -> VirtAddr {
+  VirtAddr::from_ptr(stack.as_ptr()) + stack.len()
+}
+
+/**
+ * number of bytes at the bottom of the double fault stack that have never been used
+ */
+pub fn double_fault_stack_headroom() -> usize {
+  unsafe { headroom(DOUBLE_FAULT_STACK.as_ptr(), DOUBLE_FAULT_STACK_SIZE) }
+}
+
+/**
+ * number of bytes at the bottom of the page fault stack that have never been used
+ */
+pub fn page_fault_stack_headroom() -> usize {
+  unsafe { headroom(PAGE_FAULT_STACK.as_ptr(), PAGE_FAULT_STACK_SIZE) }
+}
+
+/**
+ * in debug builds, warn about any IST stack that came close to being exhausted
+ */
+pub fn check_stack_canaries() {
+  if !cfg!(debug_assertions) {
+    return;
+  }
+  let stacks = [
+    ("double fault", double_fault_stack_headroom()),
+    ("page fault", page_fault_stack_headroom()),
+  ];
+  for &(name, headroom) in stacks.iter() {
+    if headroom < STACK_WARN_HEADROOM {
+      println!("WARNING: {} stack nearly exhausted ({} bytes unused)", name, headroom);
+    }
+  }
+}
+
+// count the canary bytes from the bottom of the stack up to the first byte that was written
+// the reads are volatile because the stack may be in use while it is inspected
+unsafe fn headroom(stack: *const u8, size: usize) -> usize {
+  (0..size)
+    .take_while(|&i| stack.add(i).read_volatile() == STACK_CANARY)
+    .count()
+}
+
 // Selectors is a struct containing the code and tss selector
 struct Selectors {
   code_selector: SegmentSelector,
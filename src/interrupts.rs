@@ -9,21 +9,30 @@
 // Secondary ATA ----> |____________|   Parallel Port 1----> |____________|
 
 use crate::gdt;
-use crate::print;
-use crate::println;
+use crate::keyboard;
+use crate::{println, serial_println};
+use crate::ps2;
+use crate::time;
 use crate::hlt_loop;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
 use lazy_static::lazy_static;
 use pic8259_simple::ChainedPics;
-use spin;
+use crate::sync;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
 
 pub const PIC_1_OFFSET: u8 = 32; // Interrupt Controller should start at port 32 (first free after 32 fault ports)
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8; // second controller goes after the first
 
 // PICS represents the diagram above, made read/write safe by a Mutex
 // this is unsafe because PIC_1_OFFSET and PIC_2_OFFSET could be invalid
-pub static PICS: spin::Mutex<ChainedPics> =
-  spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+pub static PICS: sync::Mutex<ChainedPics> =
+  sync::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+
+// how many attempts a handler makes at the PIC lock before reporting it as stuck
+const PIC_LOCK_SPINS: usize = 1_000_000;
 
 // InterruptIndex represents the index of the interrupts in the diagram above
 #[derive(Debug, Clone, Copy)]
@@ -43,6 +52,31 @@ impl InterruptIndex {
   }
 }
 
+// register_exceptions! declares every exception handler in one table
+// each line is `field => handler` with an optional `, ist = index` for handlers that need their own stack
+// it generates register_exceptions, which installs the handlers
+macro_rules! register_exceptions {
+  ($($field:ident => $handler:ident $(, ist = $ist:expr)?;)*) => {
+    fn register_exceptions(idt: &mut InterruptDescriptorTable) {
+      $(
+        #[allow(unused_variables)]
+        let options = idt.$field.set_handler_fn($handler);
+        // this is unsafe because the index must be a valid, unused IST stack
+        $(unsafe { options.set_stack_index($ist); })?
+      )*
+    }
+  };
+}
+
+// fault interrupts, adding a handler is one line here
+register_exceptions! {
+  breakpoint => breakpoint_handler;
+  invalid_opcode => invalid_opcode_handler;
+  general_protection_fault => general_protection_fault_handler;
+  page_fault => page_fault_handler, ist = gdt::PAGE_FAULT_IST_INDEX;
+  double_fault => double_fault_handler, ist = gdt::DOUBLE_FAULT_IST_INDEX;
+}
+
 // lazily initialize the IDT
 // the Interrupt Descriptor Table (IDT) maps interrupt codes to
 // their corresponding handler
@@ -51,14 +85,7 @@ lazy_static! {
     let mut idt = InterruptDescriptorTable::new();
 
     // fault interrupts
-    idt.breakpoint.set_handler_fn(breakpoint_handler);
-    idt.page_fault.set_handler_fn(page_fault_handler);
-    unsafe {
-      idt
-        .double_fault
-        .set_handler_fn(double_fault_handler)
-        .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-    }
+    register_exceptions(&mut idt);
 
     // PIC interrupts
     idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
@@ -73,6 +100,72 @@ pub fn init_idt() {
   IDT.load();
 }
 
+// names of the 32 exception vectors, as the IDT fields are named, None for reserved vectors
+const EXCEPTION_NAMES: [Option<&str>; 32] = [
+  Some("divide_error"),
+  Some("debug"),
+  Some("non_maskable_interrupt"),
+  Some("breakpoint"),
+  Some("overflow"),
+  Some("bound_range_exceeded"),
+  Some("invalid_opcode"),
+  Some("device_not_available"),
+  Some("double_fault"),
+  Some("coprocessor_segment_overrun"),
+  Some("invalid_tss"),
+  Some("segment_not_present"),
+  Some("stack_segment_fault"),
+  Some("general_protection_fault"),
+  Some("page_fault"),
+  None,
+  Some("x87_floating_point"),
+  Some("alignment_check"),
+  Some("machine_check"),
+  Some("simd_floating_point"),
+  Some("virtualization"),
+  None, None, None, None, None, None, None, None, None,
+  Some("security_exception"),
+  None,
+];
+
+/**
+ * idt_summary lists the exceptions that have a handler in the loaded IDT, in vector order
+ * it reads the gate descriptors the CPU uses (found with sidt), so a handler that was never
+ * registered or an IDT that was never loaded shows up as missing
+ */
+pub fn idt_summary() -> Vec<&'static str> {
+  use x86_64::structures::DescriptorTablePointer;
+
+  let mut pointer = DescriptorTablePointer { limit: 0, base: 0 };
+  unsafe { asm!("sidt [{}]", in(reg) &mut pointer, options(nostack, preserves_flags)) };
+  let base = pointer.base;
+  let entries = (usize::from(pointer.limit) + 1) / GATE_SIZE;
+
+  let mut summary = Vec::new();
+  for (vector, name) in EXCEPTION_NAMES.iter().enumerate().take(entries) {
+    let gate = unsafe { &*((base as usize + vector * GATE_SIZE) as *const [u8; GATE_SIZE]) };
+    if let (Some(name), true) = (name, gate_has_handler(gate)) {
+      summary.push(*name);
+    }
+  }
+  summary
+}
+
+// size of a 64-bit IDT gate descriptor
+const GATE_SIZE: usize = 16;
+
+/**
+ * gate_has_handler tells whether a gate descriptor is present and points at a handler
+ * the handler address is split over bytes 0-1, 6-7 and 8-11, the present bit is the top bit of byte 5
+ */
+fn gate_has_handler(gate: &[u8; GATE_SIZE]) -> bool {
+  let present = gate[5] & 0x80 != 0;
+  let address = u64::from(u16::from_le_bytes([gate[0], gate[1]]))
+    | u64::from(u16::from_le_bytes([gate[6], gate[7]])) << 16
+    | u64::from(u32::from_le_bytes([gate[8], gate[9], gate[10], gate[11]])) << 32;
+  present && address != 0
+}
+
 /**
  * breakpoint_handler handles breakpoint interrupts
  */
@@ -80,19 +173,126 @@ extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFra
   println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
+/**
+ * invalid_opcode_handler reports an undefined instruction and halts
+ */
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: &mut InterruptStackFrame) {
+  println!("EXCEPTION: INVALID OPCODE");
+  println!("{:#?}", stack_frame);
+  hlt_loop();
+}
+
+/**
+ * general_protection_fault_handler reports a general protection fault and halts
+ * the error code is the segment selector index involved, or 0 if the fault wasn't segment related
+ */
+extern "x86-interrupt" fn general_protection_fault_handler(
+  stack_frame: &mut InterruptStackFrame,
+  error_code: u64,
+) {
+  println!("EXCEPTION: GENERAL PROTECTION FAULT");
+  println!("Error Code: {:#x}", error_code);
+  println!("{:#?}", stack_frame);
+  hlt_loop();
+}
+
+// set while the page fault handler runs, it never returns so this is never cleared
+static IN_PAGE_FAULT: AtomicBool = AtomicBool::new(false);
+
+/**
+ * page_fault_handler reports the faulting address and access on screen and serial, then halts
+ * halting keeps an invalid access from escalating to a double or triple fault
+ */
 extern "x86-interrupt" fn page_fault_handler(
   stack_frame: &mut InterruptStackFrame,
   error_code: PageFaultErrorCode,
 ) {
   use x86_64::registers::control::Cr2;
 
-  println!("EXCEPTION: PAGE FAULT");
-  println!("Accessed Address: {:?}", Cr2::read());
-  println!("Error Code: {:?}", error_code);
+  if IN_PAGE_FAULT.swap(true, Ordering::SeqCst) {
+    nested_page_fault(Cr2::read());
+  }
+  let report = PageFaultReport {
+    address: Cr2::read(),
+    error_code,
+  };
+  println!("{}", report);
+  serial_println!("{}", report);
   println!("{:#?}", stack_frame);
+  gdt::check_stack_canaries();
+  // try_lock so a fault while the hook is being set can't deadlock here
+  if let Some(hook) = PAGE_FAULT_HOOK.try_lock().and_then(|hook| *hook) {
+    hook(&report);
+  }
+  hlt_loop();
+}
+
+/**
+ * nested_page_fault handles a page fault raised by the page fault handler itself
+ * the CPU started this fault at the top of the same IST stack, over the outer handler's frame,
+ * so neither handler may return. the outer one may hold the screen or serial lock, so only try them
+ */
+fn nested_page_fault(address: VirtAddr) -> ! {
+  use core::fmt::Write;
+
+  if let Some(mut serial) = crate::serial::SERIAL1.try_lock() {
+    let _ = writeln!(serial, "EXCEPTION: PAGE FAULT at {:#x} inside the page fault handler", address.as_u64());
+  }
   hlt_loop();
 }
 
+// PageFaultReport is what the page fault handler reports about a fault
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageFaultReport {
+  pub address: VirtAddr, // the address that was accessed, from CR2
+  pub error_code: PageFaultErrorCode,
+}
+
+impl fmt::Display for PageFaultReport {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    writeln!(f, "EXCEPTION: PAGE FAULT")?;
+    if let Some(fault) = read_only_write(self.address, self.error_code) {
+      writeln!(f, "{}", fault)?;
+    }
+    writeln!(f, "Accessed Address: {:#x}", self.address.as_u64())?;
+    write!(f, "Error Code: {:?}", self.error_code)
+  }
+}
+
+// called by the page fault handler with its report before it halts, e.g. by tests
+static PAGE_FAULT_HOOK: sync::Mutex<Option<fn(&PageFaultReport)>> = sync::Mutex::new(None);
+
+/**
+ * set_page_fault_hook has the page fault handler call hook once it has printed its report
+ * the handler still halts if the hook returns
+ */
+pub fn set_page_fault_hook(hook: fn(&PageFaultReport)) {
+  *PAGE_FAULT_HOOK.lock() = Some(hook);
+}
+
+// ReadOnlyWrite describes a page fault caused by writing to a present, read-only page
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadOnlyWrite(pub VirtAddr);
+
+impl fmt::Display for ReadOnlyWrite {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "attempted write to read-only page at {:#x}", self.0.as_u64())
+  }
+}
+
+/**
+ * read_only_write recognizes a write to a read-only page
+ * the page was present (PROTECTION_VIOLATION) and the access was a write (CAUSED_BY_WRITE)
+ */
+pub fn read_only_write(addr: VirtAddr, error_code: PageFaultErrorCode) -> Option<ReadOnlyWrite> {
+  let flags = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+  if error_code.contains(flags) {
+    Some(ReadOnlyWrite(addr))
+  } else {
+    None
+  }
+}
+
 /**
  * double_fault_handler handles a double fault
  */
@@ -107,53 +307,46 @@ extern "x86-interrupt" fn double_fault_handler(
  * timer_interrupt_handler handles interrupt from the timer in the PIC
  */
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-  print!(".");
+  time::tick();
 
   // send "end of interrupt"
-  unsafe {
-    PICS
-      .lock()
-      .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-  }
+  end_of_interrupt(InterruptIndex::Timer);
 }
 
 /**
  * keyboard_interrupt_handler handles keystrokes
+ * the scancode is handed to the keyboard module, which queues the decoded character
+ * if the controller has no byte waiting, nothing is read
  */
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-  use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-  use spin::Mutex;
-  use x86_64::instructions::port::Port;
-
-  // define static keyboard
-  lazy_static! {
-    static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = Mutex::new(
-      Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore)
-    );
-  }
-
-  let mut keyboard = KEYBOARD.lock();
-  let mut port = Port::new(0x60); // data port for PS/2 controller
-
-  // read scancode, if it is a valid value, print it
-  let scancode: u8 = unsafe { port.read() };
-  if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-    if let Some(key) = keyboard.process_keyevent(key_event) {
-      match key {
-        DecodedKey::Unicode(character) => print!("{}", character),
-        DecodedKey::RawKey(key) => print!("{:?}", key),
-      }
-    }
+  if let Some(scancode) = ps2::read_data() {
+    keyboard::add_scancode(scancode);
   }
 
   // notify end of interrupt
-  unsafe {
-    PICS
-      .lock()
-      .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+  end_of_interrupt(InterruptIndex::Keyboard);
+}
+
+/**
+ * end_of_interrupt tells the PIC the interrupt was handled
+ * if the PIC lock stays held (e.g. its holder faulted) this is reported on serial instead of hanging
+ */
+fn end_of_interrupt(index: InterruptIndex) {
+  match PICS.try_lock_timeout(PIC_LOCK_SPINS) {
+    Some(mut pics) => unsafe { pics.notify_end_of_interrupt(index.as_u8()) },
+    None => crate::serial_println!("WARNING: PIC lock stuck, no end of interrupt for {:?}", index),
   }
 }
 
+#[test_case]
+fn test_idt_summary() {
+  // the test kernel has loaded the kernel IDT during boot
+  assert_eq!(
+    idt_summary(),
+    ["breakpoint", "invalid_opcode", "double_fault", "general_protection_fault", "page_fault"]
+  );
+}
+
 // #[test_case]
 // fn test_breakpoint_exception() {
 //   x86_64::instructions::interrupts::int3();
@@ -0,0 +1,327 @@
+// keyboard.rs buffers keyboard input so kernel code can consume it.
+// the keyboard interrupt handler only decodes the scancode and pushes the character onto a
+// bounded queue, so interrupt context stays short. readers pop characters off the queue.
+// it also keeps track of which keys are held, for code that polls instead (e.g. a game loop).
+
+use crate::port::{HardwarePorts, PortIo};
+use crate::{print, ps2, time, vga_buffer};
+use alloc::string::String;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, KeyState, Keyboard, ScancodeSet1};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// number of characters the queue holds before new keystrokes are dropped
+pub const QUEUE_SIZE: usize = 100;
+
+const BACKSPACE: char = '\u{8}';
+
+// whether read_line mirrors what it echoes to the serial port
+static SERIAL_ECHO: AtomicBool = AtomicBool::new(false);
+
+// KeyQueue is a fixed size ring buffer of decoded characters
+struct KeyQueue {
+  keys: [char; QUEUE_SIZE],
+  head: usize, // index of the oldest character
+  len: usize,  // number of queued characters
+}
+
+impl KeyQueue {
+  const fn new() -> Self {
+    KeyQueue {
+      keys: ['\0'; QUEUE_SIZE],
+      head: 0,
+      len: 0,
+    }
+  }
+
+  // add a character to the back of the queue, returns false if the queue is full
+  fn push(&mut self, key: char) -> bool {
+    if self.len == QUEUE_SIZE {
+      return false;
+    }
+    self.keys[(self.head + self.len) % QUEUE_SIZE] = key;
+    self.len += 1;
+    true
+  }
+
+  // remove the character at the front of the queue
+  fn pop(&mut self) -> Option<char> {
+    if self.len == 0 {
+      return None;
+    }
+    let key = self.keys[self.head];
+    self.head = (self.head + 1) % QUEUE_SIZE;
+    self.len -= 1;
+    Some(key)
+  }
+}
+
+static QUEUE: Mutex<KeyQueue> = Mutex::new(KeyQueue::new());
+
+// one bit per KeyCode, set while the key is held down
+const PRESSED_WORDS: usize = 4; // room for 256 key codes
+const NOT_PRESSED: AtomicU64 = AtomicU64::new(0);
+static PRESSED: [AtomicU64; PRESSED_WORDS] = [NOT_PRESSED; PRESSED_WORDS];
+
+// the scancode decoder, it keeps track of shift and other modifier state between scancodes
+lazy_static! {
+  static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = Mutex::new(
+    Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore)
+  );
+}
+
+/**
+ * add_scancode decodes a scancode from the PS/2 controller and queues the resulting character
+ * called by the keyboard interrupt handler; keys without a character (arrows, F-keys) are ignored
+ * if the queue is full the keystroke is dropped, panicking in interrupt context isn't an option
+ */
+pub fn add_scancode(scancode: u8) {
+  // responses to a command (see ps2::send) that arrived late aren't keystrokes
+  if scancode == ps2::ACK || scancode == ps2::RESEND {
+    return;
+  }
+  interrupts::without_interrupts(|| {
+    let mut keyboard = KEYBOARD.lock();
+    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
+      set_pressed(key_event.code, key_event.state == KeyState::Down);
+      if let Some(DecodedKey::Unicode(character)) = keyboard.process_keyevent(key_event) {
+        QUEUE.lock().push(character);
+      }
+    }
+  });
+}
+
+/**
+ * is_pressed tells whether the key is held down right now
+ */
+pub fn is_pressed(key: KeyCode) -> bool {
+  let (word, bit) = pressed_bit(key);
+  PRESSED[word].load(Ordering::Relaxed) & bit != 0
+}
+
+/**
+ * record a key going down or up
+ */
+fn set_pressed(key: KeyCode, pressed: bool) {
+  let (word, bit) = pressed_bit(key);
+  if pressed {
+    PRESSED[word].fetch_or(bit, Ordering::Relaxed);
+  } else {
+    PRESSED[word].fetch_and(!bit, Ordering::Relaxed);
+  }
+}
+
+/**
+ * the word in PRESSED and the bit within it that track key
+ */
+fn pressed_bit(key: KeyCode) -> (usize, u64) {
+  let index = key as usize % (PRESSED_WORDS * 64);
+  (index / 64, 1 << (index % 64))
+}
+
+/**
+ * pop_char takes the oldest typed character off the queue, if there is one
+ */
+pub fn pop_char() -> Option<char> {
+  interrupts::without_interrupts(|| QUEUE.lock().pop())
+}
+
+/**
+ * read_char waits for the next typed character and returns it without echoing it
+ * the CPU halts between keystrokes, so interrupts must be enabled
+ */
+pub fn read_char() -> char {
+  loop {
+    match pop_char() {
+      Some(character) => return character,
+      None => time::wait_for_interrupt(),
+    }
+  }
+}
+
+/**
+ * set_serial_echo makes read_line echo typed characters to the serial port as well as the screen,
+ * so input shows up in a captured serial log
+ */
+pub fn set_serial_echo(enabled: bool) {
+  SERIAL_ECHO.store(enabled, Ordering::Relaxed);
+}
+
+// SerialEcho writes echoed input to the first serial port
+struct SerialEcho;
+
+impl fmt::Write for SerialEcho {
+  fn write_str(&mut self, s: &str) -> fmt::Result {
+    crate::serial_print!("{}", s);
+    Ok(())
+  }
+}
+
+/**
+ * read_line echoes typed characters and appends them to buf until enter is pressed
+ * backspace removes the last character of the line; the newline itself isn't added to buf
+ * the CPU halts between keystrokes, so interrupts must be enabled
+ */
+pub fn read_line(buf: &mut String) {
+  read_line_echoing(buf, &mut SerialEcho);
+}
+
+/**
+ * read_line_echoing is read_line with the serial echo going to the given writer, so tests can capture it
+ */
+fn read_line_echoing(buf: &mut String, serial: &mut impl fmt::Write) {
+  loop {
+    match read_char() {
+      '\n' => {
+        echo("\n", serial);
+        return;
+      }
+      BACKSPACE => {
+        if buf.pop().is_some() {
+          vga_buffer::backspace();
+          if SERIAL_ECHO.load(Ordering::Relaxed) {
+            let _ = serial.write_str("\u{8} \u{8}"); // erase on a serial terminal
+          }
+        }
+      }
+      character => {
+        buf.push(character);
+        let mut encoded = [0; 4];
+        echo(character.encode_utf8(&mut encoded), serial);
+      }
+    }
+  }
+}
+
+/**
+ * echo typed text on the screen, and to serial if serial echo is enabled
+ */
+fn echo(text: &str, serial: &mut impl fmt::Write) {
+  print!("{}", text);
+  if SERIAL_ECHO.load(Ordering::Relaxed) {
+    let _ = serial.write_str(text);
+  }
+}
+
+// TypematicError describes why the repeat settings weren't applied
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TypematicError {
+  InvalidRate(u8),  // rates go from 0 (30 repeats per second) to 31 (2 per second)
+  InvalidDelay(u8), // delays go from 0 (250 ms) to 3 (1000 ms) in 250 ms steps
+  Controller(ps2::Ps2Error),
+}
+
+// keyboard command to set the auto-repeat rate and delay
+const SET_TYPEMATIC: u8 = 0xf3;
+
+/**
+ * set_typematic sets how fast a held key repeats (rate, 0 fastest to 31 slowest)
+ * and how long before it starts repeating (delay, 0 to 3 for 250 to 1000 ms)
+ */
+pub fn set_typematic(rate: u8, delay: u8) -> Result<(), TypematicError> {
+  // like ps2::send, keep the keyboard interrupt from taking the ACKs for scancodes
+  interrupts::without_interrupts(|| set_typematic_on(&mut HardwarePorts, rate, delay))
+}
+
+fn set_typematic_on(port: &mut impl PortIo, rate: u8, delay: u8) -> Result<(), TypematicError> {
+  let encoded = encode_typematic(rate, delay)?;
+  ps2::send_to(port, &[SET_TYPEMATIC, encoded]).map_err(TypematicError::Controller)
+}
+
+/**
+ * encode_typematic packs rate into bits 0-4 and delay into bits 5-6, bit 7 must be 0
+ */
+fn encode_typematic(rate: u8, delay: u8) -> Result<u8, TypematicError> {
+  if rate > 0x1f {
+    return Err(TypematicError::InvalidRate(rate));
+  }
+  if delay > 3 {
+    return Err(TypematicError::InvalidDelay(delay));
+  }
+  Ok(delay << 5 | rate)
+}
+
+#[test_case]
+fn test_read_line_from_scancodes() {
+  // h, e, x, backspace, l, l, o, enter as scancode set 1 presses and releases
+  let scancodes = [
+    0x23, 0xa3, 0x12, 0x92, 0x2d, 0xad, 0x0e, 0x8e, 0x26, 0xa6, 0x26, 0xa6, 0x18, 0x98, 0x1c, 0x9c,
+  ];
+  for &scancode in scancodes.iter() {
+    add_scancode(scancode);
+  }
+
+  let mut line = String::new();
+  read_line(&mut line);
+  assert_eq!(line, "hello");
+  assert_eq!(pop_char(), None);
+}
+
+#[test_case]
+fn test_full_queue_drops_keys() {
+  let mut queue = KeyQueue::new();
+  for _ in 0..QUEUE_SIZE {
+    assert!(queue.push('a'));
+  }
+  assert!(!queue.push('b')); // dropped
+  assert_eq!(queue.pop(), Some('a'));
+  assert!(queue.push('c'));
+}
+
+#[test_case]
+fn test_set_typematic_sends_command() {
+  let mut port = ps2::acking_device();
+  assert_eq!(set_typematic_on(&mut port, 0x0b, 1), Ok(()));
+  assert!(port.written_to(ps2::DATA_PORT).eq([0xf3, 0x2b].iter().copied()));
+
+  assert_eq!(set_typematic_on(&mut port, 32, 0), Err(TypematicError::InvalidRate(32)));
+  assert_eq!(set_typematic_on(&mut port, 0, 4), Err(TypematicError::InvalidDelay(4)));
+  assert_eq!(port.count, 2); // nothing was sent for the invalid settings
+
+  port.set(ps2::DATA_PORT, ps2::RESEND);
+  let error = TypematicError::Controller(ps2::Ps2Error::Resend);
+  assert_eq!(set_typematic_on(&mut port, 0x0b, 1), Err(error));
+}
+
+#[test_case]
+fn test_acks_are_not_scancodes() {
+  add_scancode(ps2::ACK);
+  add_scancode(ps2::RESEND);
+  assert_eq!(pop_char(), None);
+}
+
+#[test_case]
+#[cfg(not(feature = "headless"))] // headless, the echo goes to serial instead of the screen
+fn test_serial_echo() {
+  // a, enter as scancode set 1 presses and releases
+  for &scancode in [0x1e, 0x9e, 0x1c, 0x9c].iter() {
+    add_scancode(scancode);
+  }
+
+  print!("\n");
+  set_serial_echo(true);
+  let mut line = String::new();
+  let mut serial = String::new();
+  read_line_echoing(&mut line, &mut serial);
+  set_serial_echo(false);
+
+  assert_eq!(line, "a");
+  assert_eq!(serial, "a\n");
+  let screen = vga_buffer::screen_to_string();
+  let rows: alloc::vec::Vec<&str> = screen.split('\n').collect();
+  assert_eq!(rows[vga_buffer::BUFFER_HEIGHT - 2], "a");
+}
+
+#[test_case]
+fn test_is_pressed() {
+  add_scancode(0x1e); // a down
+  assert!(is_pressed(KeyCode::A));
+  assert!(!is_pressed(KeyCode::S));
+
+  add_scancode(0x9e); // a up
+  assert!(!is_pressed(KeyCode::A));
+  assert_eq!(pop_char(), Some('a'));
+}
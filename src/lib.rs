@@ -4,6 +4,7 @@
 #![feature(custom_test_frameworks)] // enable custom test frameworks
 #![feature(abi_x86_interrupt)] // enable "x86-interrupt" calling convention
 #![feature(alloc_error_handler)] // enable alloc errors to be handled
+#![feature(asm)] // enable inline assembly
 #![test_runner(crate::test_runner)] // use test_runner for tests
 #![reexport_test_harness_main = "test_main"]
 
@@ -12,10 +13,22 @@ extern crate rlibc;
 
 // make modules available to crate
 pub mod allocator;
+pub mod boot;
+pub mod clock;
+pub mod elf;
 pub mod gdt;
 pub mod interrupts;
+pub mod keyboard;
 pub mod memory;
+pub mod pager;
+pub mod port;
+pub mod ps2;
+pub mod rand;
+pub mod rtc;
 pub mod serial;
+pub mod sync;
+pub mod time;
+pub mod util;
 pub mod vga_buffer;
 
 #[cfg(test)]
@@ -25,10 +38,17 @@ use core::panic::PanicInfo;
 #[cfg(test)]
 entry_point!(test_kernel_main);
 
+/**
+ * init brings up the CPU without the bootloader's info: the boot::CPU_PHASES (gdt, idt and pic)
+ * then enables interrupts. kernels with a BootInfo use boot::run for the full sequence instead
+ */
 pub fn init() {
-  gdt::init();
-  interrupts::init_idt();
-  unsafe { interrupts::PICS.lock().initialize() }; // initialize the Interrupt Controller
+  util::record_stack_top();
+  boot::mark_start();
+  let mut context = boot::BootContext::new(None);
+  if let Err(error) = boot::run_phases(&boot::PHASES[..boot::CPU_PHASES], &mut context) {
+    panic!("{}", error);
+  }
   x86_64::instructions::interrupts::enable(); // enable interrupts for the CPU
 }
 
@@ -39,10 +59,11 @@ fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
 
 /**
  * hlt_loop uses the hlt instruction to preserve CPU resources
+ * deferred timer work (see time::wait_for_interrupt) still runs each time the CPU wakes up
  */
 pub fn hlt_loop() -> ! {
   loop {
-    x86_64::instructions::hlt();
+    time::wait_for_interrupt();
   }
 }
 
@@ -85,8 +106,10 @@ pub fn test_panic_handler(info: &PanicInfo) -> ! {
 
 /// Entry point for `cargo test`
 #[cfg(test)]
-fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
-  init();
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+  util::record_stack_top();
+  // run the full boot sequence so tests can use the heap
+  boot::run(boot_info).expect("boot failed");
   test_main();
   hlt_loop();
 }
@@ -104,11 +127,32 @@ pub enum QemuExitCode {
   Failed = 0x11,
 }
 
+/**
+ * exit_qemu exits QEMU with one of the well known exit codes
+ */
 pub fn exit_qemu(exit_code: QemuExitCode) {
-  use x86_64::instructions::port::Port;
+  exit_qemu_code(exit_code as u32);
+}
 
-  unsafe {
-    let mut port = Port::new(0xf4);
-    port.write(exit_code as u32);
-  }
+/**
+ * exit_qemu_code exits QEMU with an arbitrary code, e.g. to report a result to a CI harness
+ * QEMU will exit with the status (code << 1) | 1
+ */
+pub fn exit_qemu_code(code: u32) {
+  write_exit_code(&mut port::HardwarePorts, code);
+}
+
+// I/O port of QEMU's isa-debug-exit device, see test-args in Cargo.toml
+const EXIT_PORT: u16 = 0xf4;
+
+fn write_exit_code(ports: &mut impl port::PortIo, code: u32) {
+  ports.write_u32(EXIT_PORT, code);
+}
+
+#[test_case]
+fn test_exit_code_written_to_port() {
+  let mut ports = port::MockPorts::new();
+  write_exit_code(&mut ports, 7);
+  write_exit_code(&mut ports, QemuExitCode::Failed as u32);
+  assert!(ports.written_to(EXIT_PORT).eq([7, 0x11].iter().copied()));
 }
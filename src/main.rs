@@ -10,7 +10,6 @@ extern crate rlibc;
 
 use alloc::{boxed::Box, vec, vec::Vec, rc::Rc};
 use bootloader::{entry_point, BootInfo};
-use cloudos::allocator;
 use cloudos::println;
 use core::panic::PanicInfo;
 
@@ -19,6 +18,11 @@ use core::panic::PanicInfo;
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
   println!("{}", info);
+  // return addresses only, symbolize them with the kernel binary on the host
+  println!("backtrace:");
+  for address in cloudos::util::backtrace(16).iter() {
+    println!("  {:#x}", address);
+  }
   cloudos::hlt_loop();
 }
 
@@ -34,19 +38,22 @@ entry_point!(kernel_main);
 // BootInfo is passed from the bootloader to the kernal with info
 // this is because of the "map_physical_memory" feature in Cargo.toml
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
-  use cloudos::memory;
-  use x86_64::VirtAddr;
+  use cloudos::boot;
 
+  cloudos::util::record_stack_top();
+  boot::mark_start();
   println!("Hello World{}", "!");
 
-  cloudos::init();
-
-  // grab reference to l4 table in virt memory
-  let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-  let mut mapper = unsafe { memory::init(phys_mem_offset) };
-  let mut frame_allocator = unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
-
-  allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+  // bring up the GDT, interrupts, memory and heap in order
+  if let Err(error) = boot::run(boot_info) {
+    panic!("{}", error);
+  }
+  // the timer is only just running, so this is usually reported in cycles
+  match boot::elapsed_ms() {
+    Some(ms) => println!("boot complete in {} ms", ms),
+    None => println!("boot complete in {} TSC cycles", boot::elapsed_cycles()),
+  }
+  println!("{}", cloudos::memory::memory_report(boot_info.memory_map.iter()));
 
   // allocate a number on the heap
   let heap_value = Box::new(41);
@@ -6,14 +6,25 @@
 // gives us the virtual address for the table which the CPU will translate into the physical address
 // when we read/write to it.
 
-use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use alloc::vec::Vec;
+use bootloader::bootinfo::{MemoryMap, MemoryRegion, MemoryRegionType};
+use core::fmt;
 use x86_64::{
   structures::paging::{
-    FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB,
+    mapper::MapToError, FrameAllocator, Mapper, MapperAllSizes, OffsetPageTable, Page, PageSize,
+    PageTable, PageTableFlags, PhysFrame, Size4KiB,
   },
   PhysAddr, VirtAddr,
 };
 
+// MemoryError describes why a mapping or translation request was rejected
+#[derive(Debug)]
+pub enum MemoryError {
+  InvalidAddress(u64), // the virtual address is not canonical
+  NotMapped(u64),      // the virtual address has no physical mapping
+  MapFailed(MapToError<Size4KiB>),
+}
+
 // initialize an OffsetPageTable
 // the OffsetPageTable is an x86 crate abstraction for mapping virtual and physical
 // memory and assumes that the virt address space is completely mapped to the physical
@@ -35,43 +46,386 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
   &mut *page_table_ptr // deref the pointer to create a mutable reference
 }
 
+// x86-64 only uses 48 bits of a virtual address and requires bits 48-63 to be copies of bit 47
+// touching a non-canonical address causes a general protection fault instead of a page fault
+pub fn is_canonical(addr: u64) -> bool {
+  let upper = addr >> 47; // bit 47 and everything above it
+  upper == 0 || upper == 0x1_ffff
+}
+
+// translate a virtual address to the physical address it is mapped to
+pub fn translate(mapper: &impl MapperAllSizes, addr: u64) -> Result<PhysAddr, MemoryError> {
+  if !is_canonical(addr) {
+    return Err(MemoryError::InvalidAddress(addr));
+  }
+  mapper
+    .translate_addr(VirtAddr::new(addr))
+    .ok_or(MemoryError::NotMapped(addr))
+}
+
+// map the page containing the virtual address addr to the given frame
+// unsafe because the caller must ensure the frame isn't already in use elsewhere
+pub unsafe fn create_mapping(
+  addr: u64,
+  frame: PhysFrame,
+  flags: PageTableFlags,
+  mapper: &mut impl Mapper<Size4KiB>,
+  frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MemoryError> {
+  if !is_canonical(addr) {
+    return Err(MemoryError::InvalidAddress(addr));
+  }
+  let page = Page::containing_address(VirtAddr::new(addr));
+  mapper
+    .map_to(page, frame, flags, frame_allocator)
+    .map_err(MemoryError::MapFailed)?
+    .flush();
+  Ok(())
+}
+
+// the first 1 MiB of physical memory (BIOS data area, VGA font tables, real-mode structures)
+// is mapped at this virtual address by map_low_memory, rather than identity mapped,
+// so that the null page stays unmapped and null pointer accesses still fault
+pub const LOW_MEMORY_START: u64 = 0x_3333_0000_0000;
+pub const LOW_MEMORY_SIZE: u64 = 1024 * 1024; // 1 MiB
+
+// map the first 1 MiB of physical memory read-only at LOW_MEMORY_START and return that address
+// so physical address p can be read at LOW_MEMORY_START + p, e.g. the BIOS data area at 0x400
+// pages already mapped to the right frame (e.g. by an earlier call) are left alone,
+// a page in the range that maps anything else fails with PageAlreadyMapped
+pub fn map_low_memory(
+  mapper: &mut impl Mapper<Size4KiB>,
+  frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<VirtAddr, MemoryError> {
+  let flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+  for offset in (0..LOW_MEMORY_SIZE).step_by(Size4KiB::SIZE as usize) {
+    let frame = PhysFrame::containing_address(PhysAddr::new(offset));
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(LOW_MEMORY_START + offset));
+    // parts of this range are reported as usable, BootInfoFrameAllocator skips all of it
+    // so these frames are never handed out and aliased elsewhere
+    let result = unsafe { create_mapping(LOW_MEMORY_START + offset, frame, flags, mapper, frame_allocator) };
+    match result {
+      Ok(()) => {}
+      Err(MemoryError::MapFailed(MapToError::PageAlreadyMapped(_)))
+        if mapper.translate_page(page).map_or(false, |existing| existing == frame) => {}
+      Err(error) => return Err(error),
+    }
+  }
+  Ok(VirtAddr::new(LOW_MEMORY_START))
+}
+
+// frames below this address are scarce (e.g. ISA DMA can only reach the first 16 MiB)
+// so general allocations only dip into them once everything above is used up
+// the first LOW_MEMORY_SIZE bytes are never handed out, they are reserved for map_low_memory
+pub const LOW_MEMORY_LIMIT: u64 = 16 * 1024 * 1024; // 16 MiB
+
 pub struct BootInfoFrameAllocator {
   memory_map: &'static MemoryMap,
-  next: usize,
+  high: FrameCursor, // frames at or above LOW_MEMORY_LIMIT
+  low: FrameCursor,  // frames from LOW_MEMORY_SIZE up to LOW_MEMORY_LIMIT
 }
 impl BootInfoFrameAllocator {
   // create a FrameAllocator from the given memory map
   pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
     BootInfoFrameAllocator {
       memory_map,
-      next: 0,
+      high: FrameCursor::new(LOW_MEMORY_LIMIT, u64::MAX),
+      low: FrameCursor::new(LOW_MEMORY_SIZE, LOW_MEMORY_LIMIT),
     }
   }
 
-  // create an iterator over the usable frames in the memory map
-  // impl Iterator allows us to return some type that implements Iterator without a specifc type
-  fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-    // get usable regions of memory
-    let regions = self.memory_map.iter();
-    let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-    // map each region to its address range
-    let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
-    // transform to an iterator of frame start addresses
-    let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096)); // create an iterator with every 4 KiB item
-
-    // create PhysFrame types from the start addresses
-    frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+  // allocate a frame that lies entirely below max_phys, e.g. for a DMA buffer
+  // frames above LOW_MEMORY_LIMIT are handed out first so that low memory is only used when it has to be
+  // returns None once no usable frame below max_phys remains
+  pub fn allocate_frame_in_range(&mut self, max_phys: PhysAddr) -> Option<PhysFrame> {
+    let max_phys = max_phys.as_u64();
+    let memory_map = self.memory_map;
+    self
+      .high
+      .take_below(memory_map, max_phys)
+      .or_else(|| self.low.take_below(memory_map, max_phys))
   }
 }
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-  // use the next availiable frame to allocate
+  // use the next availiable frame to allocate, preferring frames above LOW_MEMORY_LIMIT
   fn allocate_frame(&mut self) -> Option<PhysFrame> {
-    let frame = self.usable_frames().nth(self.next);
-    self.next += 1;
-    frame
+    let memory_map = self.memory_map;
+    self
+      .high
+      .take_below(memory_map, u64::MAX)
+      .or_else(|| self.low.take_below(memory_map, u64::MAX))
   }
 }
 
+// FrameCursor remembers where it is in the memory map so that each allocation picks up
+// where the last one stopped instead of walking the map from the start again
+struct FrameCursor {
+  start: u64,    // lowest address this cursor hands out
+  end: u64,      // addresses at or above this are never handed out
+  region: usize, // index of the memory map region being walked
+  next: u64,     // address of the next frame to hand out
+}
+
+impl FrameCursor {
+  const fn new(start: u64, end: u64) -> Self {
+    FrameCursor {
+      start,
+      end,
+      region: 0,
+      next: start,
+    }
+  }
+
+  // hand out the next usable frame if it ends at or below max_phys
+  fn take_below(&mut self, memory_map: &MemoryMap, max_phys: u64) -> Option<PhysFrame> {
+    let frame = self.peek(memory_map)?;
+    if frame_end(&frame) > max_phys {
+      return None;
+    }
+    self.next += Size4KiB::SIZE;
+    Some(frame)
+  }
+
+  // find the next usable frame without taking it, skipping past regions that are used up
+  // the memory map is sorted by address, so a region never has to be visited twice
+  fn peek(&mut self, memory_map: &MemoryMap) -> Option<PhysFrame> {
+    while let Some(region) = memory_map.get(self.region) {
+      let addr = self.next.max(region.range.start_addr()).max(self.start);
+      if addr >= self.end {
+        return None;
+      }
+      if region.region_type == MemoryRegionType::Usable && addr < region.range.end_addr() {
+        self.next = addr;
+        return Some(PhysFrame::containing_address(PhysAddr::new(addr)));
+      }
+      self.region += 1;
+    }
+    None
+  }
+}
+
+// the physical address just past the end of the frame
+fn frame_end(frame: &PhysFrame) -> u64 {
+  frame.start_address().as_u64() + Size4KiB::SIZE
+}
+
+// MemoryReport is the number of bytes of each kind of memory in the boot memory map
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryReport {
+  pub usable: u64,
+  pub in_use: u64,      // frames already handed out by the bootloader's frame allocator
+  pub kernel: u64,      // the kernel image and its stack
+  pub page_tables: u64, // page tables created by the bootloader
+  pub bootloader: u64,  // the bootloader itself and the boot info it passes on
+  pub reserved: u64,
+  pub acpi_reclaimable: u64, // ACPI tables, usable once they have been read
+  pub acpi_nvs: u64,         // ACPI non-volatile storage, never usable
+  pub bad: u64,
+  pub other: u64, // frame zero, empty and unknown region types
+}
+
+impl fmt::Display for MemoryReport {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let rows = [
+      ("usable", self.usable),
+      ("in use", self.in_use),
+      ("kernel", self.kernel),
+      ("page tables", self.page_tables),
+      ("bootloader", self.bootloader),
+      ("reserved", self.reserved),
+      ("acpi reclaimable", self.acpi_reclaimable),
+      ("acpi nvs", self.acpi_nvs),
+      ("bad", self.bad),
+      ("other", self.other),
+    ];
+    for &(name, bytes) in rows.iter() {
+      writeln!(f, "{:>16}: {} KiB", name, bytes / 1024)?;
+    }
+    Ok(())
+  }
+}
+
+// total up the memory map by region type
+pub fn memory_report<'a>(regions: impl IntoIterator<Item = &'a MemoryRegion>) -> MemoryReport {
+  let mut report = MemoryReport::default();
+  for region in regions {
+    let size = region.range.end_addr() - region.range.start_addr();
+    let total = match region.region_type {
+      MemoryRegionType::Usable => &mut report.usable,
+      MemoryRegionType::InUse => &mut report.in_use,
+      MemoryRegionType::Kernel | MemoryRegionType::KernelStack => &mut report.kernel,
+      MemoryRegionType::PageTable => &mut report.page_tables,
+      MemoryRegionType::Bootloader | MemoryRegionType::BootInfo | MemoryRegionType::Package => {
+        &mut report.bootloader
+      }
+      MemoryRegionType::Reserved => &mut report.reserved,
+      MemoryRegionType::AcpiReclaimable => &mut report.acpi_reclaimable,
+      MemoryRegionType::AcpiNvs => &mut report.acpi_nvs,
+      MemoryRegionType::BadMemory => &mut report.bad,
+      _ => &mut report.other,
+    };
+    *total += size;
+  }
+  report
+}
+
+// Mapping is one present page (4 KiB, 2 MiB or 1 GiB) and where it points
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+  pub virt: u64,
+  pub phys: u64,
+  pub size: u64,
+  pub flags: PageTableFlags, // without ACCESSED and DIRTY, which the CPU sets on its own
+}
+
+impl fmt::Display for Mapping {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{:#x} -> {:#x} ({} KiB, {:?})", self.virt, self.phys, self.size / 1024, self.flags)
+  }
+}
+
+// MappingSnapshot is every present page in the address space, ordered by virtual address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MappingSnapshot {
+  pub mappings: Vec<Mapping>,
+}
+
+// MappingDiff is what changed in the address space between two snapshots
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MappingDiff {
+  pub added: Vec<Mapping>,
+  pub removed: Vec<Mapping>,
+  pub changed: Vec<(Mapping, Mapping)>, // same page, different frame or flags (before, after)
+}
+
+impl fmt::Display for MappingDiff {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    for mapping in self.added.iter() {
+      writeln!(f, "+ {}", mapping)?;
+    }
+    for mapping in self.removed.iter() {
+      writeln!(f, "- {}", mapping)?;
+    }
+    for (before, after) in self.changed.iter() {
+      writeln!(f, "~ {} => {}", before, after)?;
+    }
+    Ok(())
+  }
+}
+
+// record every present page in the active page tables
+// unsafe for the same reason as init: all physical memory must be mapped at physical_memory_offset
+pub unsafe fn snapshot_mappings(physical_memory_offset: VirtAddr) -> MappingSnapshot {
+  let mut mappings = Vec::new();
+  let level_4_table = active_level_4_table(physical_memory_offset);
+  snapshot_table(level_4_table, 4, 0, physical_memory_offset, &mut mappings);
+  MappingSnapshot { mappings }
+}
+
+// add the pages under a page table of the given level (4 to 1) that starts at virtual address base
+unsafe fn snapshot_table(
+  table: &PageTable,
+  level: u32,
+  base: u64,
+  physical_memory_offset: VirtAddr,
+  mappings: &mut Vec<Mapping>,
+) {
+  let entry_size = Size4KiB::SIZE << (9 * (level - 1)); // bytes covered by one entry
+  for (index, entry) in table.iter().enumerate() {
+    let flags = entry.flags();
+    if !flags.contains(PageTableFlags::PRESENT) {
+      continue;
+    }
+    let mut virt = base + index as u64 * entry_size;
+    if level == 4 && index >= 256 {
+      virt |= 0xffff_0000_0000_0000; // sign extend into the upper half
+    }
+
+    if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+      mappings.push(Mapping {
+        virt,
+        phys: entry.addr().as_u64(),
+        size: entry_size,
+        flags: flags - PageTableFlags::ACCESSED - PageTableFlags::DIRTY,
+      });
+    } else {
+      let next = physical_memory_offset + entry.addr().as_u64();
+      let next_table = &*next.as_ptr::<PageTable>();
+      snapshot_table(next_table, level - 1, virt, physical_memory_offset, mappings);
+    }
+  }
+}
+
+// compare two snapshots, both are ordered by virtual address so they can be walked side by side
+pub fn diff(before: &MappingSnapshot, after: &MappingSnapshot) -> MappingDiff {
+  let mut result = MappingDiff::default();
+  let mut old = before.mappings.iter().peekable();
+  let mut new = after.mappings.iter().peekable();
+  loop {
+    match (old.peek(), new.peek()) {
+      (Some(&&a), Some(&&b)) if a.virt == b.virt => {
+        if a != b {
+          result.changed.push((a, b));
+        }
+        old.next();
+        new.next();
+      }
+      (Some(&&a), Some(&&b)) if a.virt < b.virt => {
+        result.removed.push(a);
+        old.next();
+      }
+      (_, Some(&&b)) => {
+        result.added.push(b);
+        new.next();
+      }
+      (Some(&&a), None) => {
+        result.removed.push(a);
+        old.next();
+      }
+      (None, None) => return result,
+    }
+  }
+}
+
+#[test_case]
+fn test_memory_report() {
+  use bootloader::bootinfo::FrameRange;
+
+  let region = |start: u64, end: u64, region_type: MemoryRegionType| MemoryRegion {
+    range: FrameRange::new(start, end),
+    region_type,
+  };
+  let regions = [
+    region(0x0, 0x1000, MemoryRegionType::FrameZero),
+    region(0x1000, 0x9_f000, MemoryRegionType::Usable),
+    region(0x9_f000, 0x10_0000, MemoryRegionType::Reserved),
+    region(0x10_0000, 0x20_0000, MemoryRegionType::Usable),
+    region(0x20_0000, 0x28_0000, MemoryRegionType::Kernel),
+    region(0x28_0000, 0x29_0000, MemoryRegionType::KernelStack),
+    region(0x29_0000, 0x29_4000, MemoryRegionType::PageTable),
+    region(0x7fe_0000, 0x800_0000, MemoryRegionType::AcpiReclaimable),
+  ];
+
+  let report = memory_report(regions.iter());
+  assert_eq!(report.usable, 0x9_e000 + 0x10_0000);
+  assert_eq!(report.reserved, 0x6_1000);
+  assert_eq!(report.kernel, 0x8_0000 + 0x1_0000);
+  assert_eq!(report.page_tables, 0x4000);
+  assert_eq!(report.acpi_reclaimable, 0x2_0000);
+  assert_eq!(report.other, 0x1000);
+  assert_eq!(report.in_use, 0);
+  assert_eq!(report.acpi_nvs, 0);
+}
+
+#[test_case]
+fn test_is_canonical() {
+  assert!(is_canonical(0));
+  assert!(is_canonical(0x0000_7fff_ffff_ffff)); // top of the lower half
+  assert!(is_canonical(0xffff_8000_0000_0000)); // bottom of the upper half
+  assert!(!is_canonical(0x0000_8000_0000_0000));
+  assert!(!is_canonical(0xffff_7fff_ffff_ffff));
+}
+
 /* The x86 mapper abstraction makes the below obsolete but I'm leaving it here anyway for reference
 /**
  * provide an unsafe wrapper around the _translate_addr function
@@ -0,0 +1,73 @@
+// pager.rs shows long output one screen at a time, like more.
+// between pages it waits on the keyboard queue: space shows the next page,
+// enter shows one more line and q stops.
+
+use crate::{keyboard, print, println, vga_buffer};
+
+// lines per page, the bottom row is kept for the prompt
+pub const PAGE_HEIGHT: usize = vga_buffer::BUFFER_HEIGHT - 1;
+
+const PROMPT: &str = "-- more (space, enter, q) --";
+
+/**
+ * page prints lines a screenful at a time and returns how many were shown before the end or q
+ * the CPU halts while waiting for a key, so interrupts must be enabled
+ */
+pub fn page(lines: &[&str]) -> usize {
+  let mut shown = 0;
+  let mut budget = PAGE_HEIGHT; // lines to show before prompting again
+  while shown < lines.len() {
+    if budget == 0 {
+      print!("{}", PROMPT);
+      let key = wait_for_key();
+      erase_prompt();
+      budget = match key {
+        ' ' => PAGE_HEIGHT,
+        '\n' => 1,
+        _ => return shown,
+      };
+    }
+    println!("{}", lines[shown]);
+    shown += 1;
+    budget -= 1;
+  }
+  shown
+}
+
+/**
+ * wait for one of the pager keys, anything else is ignored
+ */
+fn wait_for_key() -> char {
+  loop {
+    match keyboard::read_char() {
+      key @ ' ' | key @ '\n' | key @ 'q' => return key,
+      _ => continue,
+    }
+  }
+}
+
+/**
+ * remove the prompt so it doesn't stay in the output
+ */
+fn erase_prompt() {
+  for _ in 0..PROMPT.len() {
+    vga_buffer::backspace();
+  }
+}
+
+#[test_case]
+fn test_page_stops_at_q() {
+  use alloc::{format, string::String, vec::Vec};
+
+  let owned: Vec<String> = (0..50).map(|i| format!("pager line {}", i)).collect();
+  let lines: Vec<&str> = owned.iter().map(|line| line.as_str()).collect();
+
+  // space, enter, then q, as scancode set 1 presses and releases
+  for &scancode in [0x39, 0xb9, 0x1c, 0x9c, 0x10, 0x90].iter() {
+    keyboard::add_scancode(scancode);
+  }
+
+  // one page, a second page, one more line, then stop
+  assert_eq!(page(&lines), PAGE_HEIGHT * 2 + 1);
+  assert_eq!(keyboard::pop_char(), None);
+}
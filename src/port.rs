@@ -0,0 +1,130 @@
+// port.rs puts the I/O port space behind one trait, so code driving a device through its ports
+// (a UART, the PS/2 controller, QEMU's exit device) can be tested against MockPorts.
+
+use x86_64::instructions::port::Port;
+
+// PortIo reads and writes I/O ports
+pub(crate) trait PortIo {
+  fn read(&mut self, port: u16) -> u8;
+  fn write(&mut self, port: u16, value: u8);
+  fn write_u32(&mut self, port: u16, value: u32); // for devices with 32 bit registers
+}
+
+// HardwarePorts is the real I/O port space
+pub(crate) struct HardwarePorts;
+
+impl PortIo for HardwarePorts {
+  fn read(&mut self, port: u16) -> u8 {
+    unsafe { Port::new(port).read() }
+  }
+
+  fn write(&mut self, port: u16, value: u8) {
+    unsafe { Port::new(port).write(value) }
+  }
+
+  fn write_u32(&mut self, port: u16, value: u32) {
+    unsafe { Port::new(port).write(value) }
+  }
+}
+
+// MockPorts answers reads with the values set for each port and records every write in order
+// ports without a value read as 0xff, like an empty bus
+#[cfg(test)]
+pub(crate) struct MockPorts {
+  pub loopback: bool, // reads return the last byte written instead, like a UART in loopback mode
+  values: [Option<(u16, u8)>; 4],
+  last_written: u8,
+  pub written: [(u16, u32); 8],
+  pub count: usize, // how many entries of written are used
+  pub lowest: u16,  // the range of ports read or written so far
+  pub highest: u16,
+}
+
+#[cfg(test)]
+impl MockPorts {
+  pub fn new() -> Self {
+    MockPorts {
+      loopback: false,
+      values: [None; 4],
+      last_written: 0,
+      written: [(0, 0); 8],
+      count: 0,
+      lowest: u16::MAX,
+      highest: 0,
+    }
+  }
+
+  /**
+   * set makes port read as value from now on
+   */
+  pub fn set(&mut self, port: u16, value: u8) {
+    let slot = self
+      .values
+      .iter()
+      .position(|entry| matches!(entry, Some((p, _)) if *p == port))
+      .or_else(|| self.values.iter().position(Option::is_none))
+      .expect("MockPorts holds values for 4 ports");
+    self.values[slot] = Some((port, value));
+  }
+
+  /**
+   * written_to returns the values written to port, oldest first
+   */
+  pub fn written_to(&self, port: u16) -> impl Iterator<Item = u32> + '_ {
+    self.written[..self.count]
+      .iter()
+      .filter(move |(p, _)| *p == port)
+      .map(|&(_, value)| value)
+  }
+
+  fn touch(&mut self, port: u16) {
+    self.lowest = self.lowest.min(port);
+    self.highest = self.highest.max(port);
+  }
+
+  fn record(&mut self, port: u16, value: u32) {
+    self.touch(port);
+    self.written[self.count] = (port, value);
+    self.count += 1;
+  }
+}
+
+#[cfg(test)]
+impl PortIo for MockPorts {
+  fn read(&mut self, port: u16) -> u8 {
+    self.touch(port);
+    if self.loopback {
+      return self.last_written;
+    }
+    self
+      .values
+      .iter()
+      .flatten()
+      .find(|(p, _)| *p == port)
+      .map_or(0xff, |&(_, value)| value)
+  }
+
+  fn write(&mut self, port: u16, value: u8) {
+    self.last_written = value;
+    self.record(port, u32::from(value));
+  }
+
+  fn write_u32(&mut self, port: u16, value: u32) {
+    self.record(port, value);
+  }
+}
+
+#[test_case]
+fn test_mock_ports() {
+  let mut ports = MockPorts::new();
+  assert_eq!(ports.read(0x60), 0xff);
+  ports.set(0x60, 0x1e);
+  ports.set(0x60, 0x2e);
+  assert_eq!(ports.read(0x60), 0x2e);
+
+  ports.write(0x64, 1);
+  ports.write_u32(0xf4, 0x11);
+  ports.write(0x64, 2);
+  assert!(ports.written_to(0x64).eq([1, 2].iter().copied()));
+  assert_eq!((ports.lowest, ports.highest), (0x60, 0xf4));
+}
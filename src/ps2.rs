@@ -0,0 +1,129 @@
+// ps2.rs talks to the PS/2 controller, which the keyboard is attached to.
+// bytes for the keyboard are written to the data port once the controller's input buffer is empty,
+// and bytes from the keyboard are only read once the status port says one is waiting.
+// the keyboard answers every byte it is sent with ACK or RESEND.
+
+#[cfg(test)]
+use crate::port::MockPorts;
+use crate::port::{HardwarePorts, PortIo};
+use x86_64::instructions::interrupts;
+
+pub const DATA_PORT: u16 = 0x60;
+pub const STATUS_PORT: u16 = 0x64;
+
+// status register bits
+const OUTPUT_BUFFER_FULL: u8 = 1 << 0; // a byte from the device is waiting on the data port
+const INPUT_BUFFER_FULL: u8 = 1 << 1; // the controller hasn't taken the last byte yet
+
+// the keyboard's responses to a byte sent to it
+pub const ACK: u8 = 0xfa;
+pub const RESEND: u8 = 0xfe;
+
+// how many times to poll the status register before giving up
+const TIMEOUT_SPINS: usize = 100_000;
+// read_data is called by the keyboard interrupt, which only fires once a byte is waiting,
+// so it gives up much sooner (e.g. when send already took the byte that raised the interrupt)
+const READ_TIMEOUT_SPINS: usize = 1_000;
+
+// Ps2Error describes a failed exchange with the controller
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error {
+  Timeout,                // the controller didn't become ready or the device didn't answer in time
+  Resend,                 // the device asked for the byte again
+  UnexpectedResponse(u8), // the device answered with something other than ACK
+}
+
+/**
+ * send writes bytes to the device on the first PS/2 port, e.g. a keyboard command and its argument,
+ * reading the device's response after each one
+ * interrupts are off for the exchange so the keyboard handler can't take the responses for scancodes
+ */
+pub fn send(bytes: &[u8]) -> Result<(), Ps2Error> {
+  interrupts::without_interrupts(|| send_to(&mut HardwarePorts, bytes))
+}
+
+pub(crate) fn send_to(port: &mut impl PortIo, bytes: &[u8]) -> Result<(), Ps2Error> {
+  for &byte in bytes {
+    wait_until_ready(port)?;
+    port.write(DATA_PORT, byte);
+    match read_data_from(port, TIMEOUT_SPINS) {
+      Some(ACK) => {}
+      Some(RESEND) => return Err(Ps2Error::Resend),
+      Some(response) => return Err(Ps2Error::UnexpectedResponse(response)),
+      None => return Err(Ps2Error::Timeout),
+    }
+  }
+  Ok(())
+}
+
+/**
+ * read_data reads a byte from the device on the first PS/2 port, e.g. a scancode
+ * returns None if no byte arrives before the timeout, rather than whatever stale value is on the port
+ */
+pub fn read_data() -> Option<u8> {
+  read_data_from(&mut HardwarePorts, READ_TIMEOUT_SPINS)
+}
+
+pub(crate) fn read_data_from(port: &mut impl PortIo, spins: usize) -> Option<u8> {
+  for _ in 0..spins {
+    if port.read(STATUS_PORT) & OUTPUT_BUFFER_FULL != 0 {
+      return Some(port.read(DATA_PORT));
+    }
+  }
+  None
+}
+
+/**
+ * wait until the controller's input buffer is empty so it can take another byte
+ */
+fn wait_until_ready(port: &mut impl PortIo) -> Result<(), Ps2Error> {
+  for _ in 0..TIMEOUT_SPINS {
+    if port.read(STATUS_PORT) & INPUT_BUFFER_FULL == 0 {
+      return Ok(());
+    }
+  }
+  Err(Ps2Error::Timeout)
+}
+
+/**
+ * acking_device is a controller whose keyboard acknowledges every byte
+ */
+#[cfg(test)]
+pub(crate) fn acking_device() -> MockPorts {
+  let mut port = MockPorts::new();
+  port.set(STATUS_PORT, OUTPUT_BUFFER_FULL);
+  port.set(DATA_PORT, ACK);
+  port
+}
+
+#[test_case]
+fn test_send_times_out_when_busy() {
+  let mut port = MockPorts::new();
+  port.set(STATUS_PORT, INPUT_BUFFER_FULL);
+  assert_eq!(send_to(&mut port, &[0xf4]), Err(Ps2Error::Timeout));
+  assert_eq!(port.count, 0);
+}
+
+#[test_case]
+fn test_send_reads_responses() {
+  let mut port = acking_device();
+  assert_eq!(send_to(&mut port, &[0xf4]), Ok(()));
+
+  port.set(DATA_PORT, RESEND);
+  assert_eq!(send_to(&mut port, &[0xf4]), Err(Ps2Error::Resend));
+
+  port.set(STATUS_PORT, 0); // the device never answers
+  assert_eq!(send_to(&mut port, &[0xf4]), Err(Ps2Error::Timeout));
+}
+
+#[test_case]
+fn test_read_data_waits_for_output_buffer() {
+  let mut port = MockPorts::new();
+  port.set(STATUS_PORT, 0);
+  port.set(DATA_PORT, 0xff); // stale byte on the data port
+  assert_eq!(read_data_from(&mut port, READ_TIMEOUT_SPINS), None);
+
+  port.set(STATUS_PORT, OUTPUT_BUFFER_FULL);
+  port.set(DATA_PORT, 0x1e);
+  assert_eq!(read_data_from(&mut port, READ_TIMEOUT_SPINS), Some(0x1e));
+}
@@ -0,0 +1,49 @@
+// rand.rs is a small pseudo random number generator for tests and anything else that needs
+// reproducible randomness. it is NOT suitable for anything security related.
+
+// XorShift64 is Marsaglia's xorshift generator with a 64 bit state
+pub struct XorShift64 {
+  state: u64,
+}
+
+impl XorShift64 {
+  /**
+   * create a generator from a seed, the same seed always produces the same sequence
+   * a seed of 0 would only ever produce 0, so it is replaced with a fixed nonzero value
+   */
+  pub const fn new(seed: u64) -> Self {
+    XorShift64 {
+      state: if seed == 0 { 0x2545_f491_4f6c_dd1d } else { seed },
+    }
+  }
+
+  /**
+   * get the next number in the sequence
+   */
+  pub fn next_u64(&mut self) -> u64 {
+    let mut x = self.state;
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    self.state = x;
+    x
+  }
+
+  /**
+   * get a number in the range [low, high), high must be greater than low
+   */
+  pub fn range(&mut self, low: u64, high: u64) -> u64 {
+    low + self.next_u64() % (high - low)
+  }
+}
+
+#[test_case]
+fn test_xorshift_is_reproducible() {
+  let mut a = XorShift64::new(42);
+  let mut b = XorShift64::new(42);
+  for _ in 0..100 {
+    let value = a.range(10, 20);
+    assert_eq!(value, b.range(10, 20));
+    assert!(value >= 10 && value < 20);
+  }
+}
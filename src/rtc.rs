@@ -0,0 +1,96 @@
+// rtc.rs reads the wall clock time from the CMOS real time clock.
+// the CMOS is accessed by writing a register number to port 0x70 and reading its value from 0x71.
+
+use x86_64::instructions::port::Port;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+// CMOS registers
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+
+// RtcTime is a time of day in 24 hour format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtcTime {
+  pub hours: u8,
+  pub minutes: u8,
+  pub seconds: u8,
+}
+
+// status register A bit that is set while the RTC is about to update or updating the time
+const UPDATE_IN_PROGRESS: u8 = 0x80;
+
+/**
+ * read_time reads the current time of day from the RTC, waiting out an update in progress
+ * the wait can take a couple of milliseconds, so don't call it from an interrupt handler
+ */
+pub fn read_time() -> RtcTime {
+  loop {
+    if let Some(time) = try_read_time() {
+      return time;
+    }
+  }
+}
+
+/**
+ * try_read_time reads the current time of day, or returns None straight away if the RTC is updating
+ * the flag goes up 244 us before an update starts, so the registers are stable while they're read
+ */
+pub fn try_read_time() -> Option<RtcTime> {
+  if read_register(STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+    return None;
+  }
+
+  let seconds = read_register(SECONDS);
+  let minutes = read_register(MINUTES);
+  let hours = read_register(HOURS);
+  let status_b = read_register(STATUS_B);
+  Some(decode(seconds, minutes, hours, status_b))
+}
+
+/**
+ * decode converts raw register values to a 24 hour time
+ * status register B says whether the values are BCD (bit 2 clear) and 12 hour (bit 1 clear)
+ */
+fn decode(seconds: u8, minutes: u8, hours: u8, status_b: u8) -> RtcTime {
+  let binary = status_b & 0x04 != 0;
+  let twenty_four_hour = status_b & 0x02 != 0;
+  let convert = |value: u8| if binary { value } else { (value >> 4) * 10 + (value & 0x0f) };
+
+  // in 12 hour mode the top bit of the hours marks PM
+  let pm = hours & 0x80 != 0;
+  let mut hours = convert(hours & 0x7f);
+  if !twenty_four_hour {
+    hours = hours % 12 + if pm { 12 } else { 0 };
+  }
+
+  RtcTime {
+    hours,
+    minutes: convert(minutes),
+    seconds: convert(seconds),
+  }
+}
+
+fn read_register(register: u8) -> u8 {
+  let mut address: Port<u8> = Port::new(CMOS_ADDRESS);
+  let mut data: Port<u8> = Port::new(CMOS_DATA);
+  unsafe {
+    address.write(register);
+    data.read()
+  }
+}
+
+#[test_case]
+fn test_decode() {
+  // BCD, 24 hour
+  let time = decode(0x59, 0x30, 0x23, 0x02);
+  assert_eq!(time, RtcTime { hours: 23, minutes: 30, seconds: 59 });
+  // binary, 12 hour, 12:05:07 AM and PM
+  assert_eq!(decode(7, 5, 12, 0x04).hours, 0);
+  assert_eq!(decode(7, 5, 0x80 | 12, 0x04).hours, 12);
+  assert_eq!(decode(7, 5, 0x80 | 3, 0x04).hours, 15);
+}
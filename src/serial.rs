@@ -1,16 +1,93 @@
+#[cfg(test)]
+use crate::port::MockPorts;
+use crate::port::{HardwarePorts, PortIo};
+use heapless::consts::U16;
 use lazy_static::lazy_static;
 use spin::Mutex;
 use uart_16550::SerialPort;
 
+// I/O port base addresses of the first two serial ports
+pub const COM1_BASE: u16 = 0x3f8;
+pub const COM2_BASE: u16 = 0x2f8; // used as a separate channel for verbose kernel logs
+
+// UART register offsets from the base address
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+
+const LOOPBACK: u8 = 0x10; // modem control bit that feeds transmitted bytes back into the receiver
+const DATA_READY: u8 = 0x01; // line status bit set while a received byte is waiting
+
 // create a lazy static reference to the first serial port to ensure a single initialization
 lazy_static! {
   pub static ref SERIAL1: Mutex<SerialPort> = {
-    let mut serial_port = unsafe { SerialPort::new(0x3f8) };
+    let mut serial_port = unsafe { SerialPort::new(COM1_BASE) };
     serial_port.init();
     Mutex::new(serial_port)
   };
 }
 
+// the second serial port, None if the machine doesn't have one
+lazy_static! {
+  pub static ref SERIAL2: Mutex<Option<SerialPort>> = {
+    let serial_port = if detect_uart(&mut HardwarePorts, COM2_BASE) {
+      let mut serial_port = unsafe { SerialPort::new(COM2_BASE) };
+      serial_port.init();
+      Some(serial_port)
+    } else {
+      None
+    };
+    Mutex::new(serial_port)
+  };
+}
+
+/**
+ * detect_uart checks for a UART at base by putting it in loopback mode and
+ * checking that a byte written to it comes back
+ */
+fn detect_uart(io: &mut impl PortIo, base: u16) -> bool {
+  const TEST_BYTE: u8 = 0xae;
+
+  io.write(base + MODEM_CONTROL, 0x1e); // loopback mode with OUT1, OUT2 and RTS set
+  io.write(base, TEST_BYTE);
+  let present = io.read(base) == TEST_BYTE;
+  io.write(base + MODEM_CONTROL, 0x0f); // back to normal operation
+  present
+}
+
+/**
+ * capture_loopback runs f with COM1 in loopback mode and returns what f wrote to it,
+ * e.g. to check that a line really went out on serial. nothing reaches the host meanwhile
+ * the UART only holds 16 received bytes, anything f writes past that is lost
+ */
+pub fn capture_loopback(f: impl FnOnce()) -> heapless::Vec<u8, U16> {
+  use x86_64::instructions::interrupts;
+
+  interrupts::without_interrupts(|| capture_with(&mut HardwarePorts, COM1_BASE, f))
+}
+
+fn capture_with(io: &mut impl PortIo, base: u16, f: impl FnOnce()) -> heapless::Vec<u8, U16> {
+  read_received(io, base); // anything already waiting wasn't written by f
+
+  let modem_control = io.read(base + MODEM_CONTROL);
+  io.write(base + MODEM_CONTROL, modem_control | LOOPBACK);
+  f();
+  let captured = read_received(io, base);
+  io.write(base + MODEM_CONTROL, modem_control);
+  captured
+}
+
+/**
+ * read_received empties the UART's receive FIFO, it stops after 16 bytes in case the port
+ * isn't there and reads as all ones
+ */
+fn read_received(io: &mut impl PortIo, base: u16) -> heapless::Vec<u8, U16> {
+  let mut received = heapless::Vec::new();
+  while received.len() < received.capacity() && io.read(base + LINE_STATUS) & DATA_READY != 0 {
+    let _ = received.push(io.read(base));
+  }
+  received
+}
+
 // macros to enable easy writing to the serial port 0x3f8
 
 #[doc(hidden)]
@@ -26,6 +103,34 @@ pub fn _print(args: ::core::fmt::Arguments) {
   });
 }
 
+#[doc(hidden)]
+pub fn _print2(args: ::core::fmt::Arguments) {
+  use core::fmt::Write;
+  use x86_64::instructions::interrupts;
+
+  // without a second serial port the output is dropped
+  interrupts::without_interrupts(|| {
+    if let Some(serial) = SERIAL2.lock().as_mut() {
+      serial.write_fmt(args).expect("Printing to serial failed");
+    }
+  });
+}
+
+/// Prints each line followed by a newline without letting other serial output in between.
+pub fn print_block(lines: &[&str]) {
+  use core::fmt::Write;
+  use x86_64::instructions::interrupts;
+
+  interrupts::without_interrupts(|| {
+    let mut serial = SERIAL1.lock();
+    for line in lines {
+      serial
+        .write_fmt(format_args!("{}\n", line))
+        .expect("Printing to serial failed");
+    }
+  });
+}
+
 /// Prints to the host through the serial interface.
 #[macro_export]
 macro_rules! serial_print {
@@ -42,3 +147,38 @@ macro_rules! serial_println {
     ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(
         concat!($fmt, "\n"), $($arg)*));
 }
+
+/// Prints to the second serial port (COM2), if there is one.
+#[macro_export]
+macro_rules! serial2_print {
+    ($($arg:tt)*) => {
+        $crate::serial::_print2(format_args!($($arg)*));
+    };
+}
+
+/// Prints to the second serial port (COM2), if there is one, appending a newline.
+#[macro_export]
+macro_rules! serial2_println {
+    () => ($crate::serial2_print!("\n"));
+    ($fmt:expr) => ($crate::serial2_print!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => ($crate::serial2_print!(
+        concat!($fmt, "\n"), $($arg)*));
+}
+
+#[test_case]
+fn test_detect_com2_uses_its_own_base() {
+  let mut ports = MockPorts::new();
+  ports.loopback = true;
+  assert!(detect_uart(&mut ports, COM2_BASE));
+  assert!(ports.count > 0);
+  assert!(ports.lowest >= COM2_BASE && ports.highest < COM2_BASE + 8);
+
+  let mut ports = MockPorts::new();
+  assert!(!detect_uart(&mut ports, COM2_BASE));
+}
+
+#[test_case]
+fn test_capture_loopback() {
+  let captured = capture_loopback(|| crate::serial_print!("abc"));
+  assert_eq!(&captured[..], b"abc");
+}
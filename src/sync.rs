@@ -0,0 +1,57 @@
+// sync.rs wraps spin::Mutex for driver code that must not hang on a stuck lock.
+// if a handler faults while holding a lock, every later lock() spins forever;
+// try_lock_timeout gives up after a bounded number of attempts so the caller can report it.
+
+use core::sync::atomic::spin_loop_hint;
+
+pub use spin::MutexGuard;
+
+// Mutex is a spin lock that can also be taken with a spin budget
+pub struct Mutex<T> {
+  inner: spin::Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+  pub const fn new(value: T) -> Self {
+    Mutex {
+      inner: spin::Mutex::new(value),
+    }
+  }
+
+  /**
+   * lock spins until the lock is free
+   */
+  pub fn lock(&self) -> MutexGuard<T> {
+    self.inner.lock()
+  }
+
+  /**
+   * try_lock takes the lock only if it is free right now
+   */
+  pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+    self.inner.try_lock()
+  }
+
+  /**
+   * try_lock_timeout tries to take the lock up to spins times, returning None if it stays held
+   */
+  pub fn try_lock_timeout(&self, spins: usize) -> Option<MutexGuard<T>> {
+    for _ in 0..spins {
+      if let Some(guard) = self.inner.try_lock() {
+        return Some(guard);
+      }
+      spin_loop_hint();
+    }
+    None
+  }
+}
+
+#[test_case]
+fn test_try_lock_timeout() {
+  let mutex = Mutex::new(5);
+  let guard = mutex.lock();
+  assert!(mutex.try_lock_timeout(1000).is_none());
+
+  core::mem::drop(guard);
+  assert_eq!(mutex.try_lock_timeout(1000).map(|guard| *guard), Some(5));
+}
@@ -0,0 +1,191 @@
+// time.rs keeps track of elapsed time by counting timer interrupts.
+// the PIT is left at its power-on configuration, so it fires at about 18.2 Hz.
+
+use crate::{clock, print};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// the PIT's input clock and the default divisor it divides it by (a reload value of 0 means 65536)
+pub const PIT_FREQUENCY_HZ: u64 = 1_193_182;
+pub const PIT_DIVISOR: u64 = 65536;
+
+// number of timer interrupts since interrupts were enabled
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+// TickIndicator is what, if anything, the timer prints to show the kernel is alive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TickIndicator {
+  None,
+  Dot { every: u64 }, // print a '.' once every `every` ticks, 0 means DEFAULT_DOT_EVERY
+}
+
+// one dot per 18 ticks is about one per second at the PIT's default frequency
+pub const DEFAULT_DOT_EVERY: u64 = 18;
+
+// the tick indicator, 0 means none, otherwise the number of ticks per dot
+static DOT_EVERY: AtomicU64 = AtomicU64::new(0);
+
+/**
+ * set_tick_indicator chooses what the timer prints, no indicator is the default
+ */
+pub fn set_tick_indicator(indicator: TickIndicator) {
+  let every = match indicator {
+    TickIndicator::None => 0,
+    TickIndicator::Dot { every: 0 } => DEFAULT_DOT_EVERY,
+    TickIndicator::Dot { every } => every,
+  };
+  DOT_EVERY.store(every, Ordering::Relaxed);
+}
+
+/**
+ * tick advances the counter, called once per timer interrupt
+ */
+pub(crate) fn tick() {
+  let ticks = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+  if dot_due(ticks) {
+    print!(".");
+  }
+  calibrate_tsc(ticks);
+  clock::on_tick();
+}
+
+/**
+ * dot_due tells whether the tick indicator prints a dot on the given tick
+ */
+fn dot_due(ticks: u64) -> bool {
+  let every = DOT_EVERY.load(Ordering::Relaxed);
+  every != 0 && ticks % every == 0
+}
+
+/**
+ * ticks returns the number of timer interrupts so far
+ * it is a plain atomic load, so it is safe to call with interrupts enabled
+ */
+pub fn ticks() -> u64 {
+  TICKS.load(Ordering::Relaxed)
+}
+
+/**
+ * ms_to_ticks converts milliseconds to timer ticks, rounding up so a sleep is never too short
+ */
+pub fn ms_to_ticks(ms: u64) -> u64 {
+  let ticks_times_1000 = ms * PIT_FREQUENCY_HZ / PIT_DIVISOR;
+  (ticks_times_1000 + 999) / 1000
+}
+
+/**
+ * sleep_ticks halts the CPU until at least n more timer interrupts have happened
+ * interrupts must be enabled or this never returns
+ */
+pub fn sleep_ticks(n: u64) {
+  let target = ticks() + n;
+  while ticks() < target {
+    wait_for_interrupt();
+  }
+}
+
+/**
+ * wait_for_interrupt halts the CPU until the next interrupt, then does the work the timer
+ * handler deferred (repainting the clock) now that it's back outside interrupt context
+ */
+pub fn wait_for_interrupt() {
+  x86_64::instructions::hlt();
+  clock::update();
+}
+
+/**
+ * sleep_ms halts the CPU for at least ms milliseconds, rounded up to whole ticks (~55 ms each)
+ */
+pub fn sleep_ms(ms: u64) {
+  sleep_ticks(ms_to_ticks(ms));
+}
+
+/**
+ * rdtsc reads the CPU's time stamp counter, which counts cycles at a constant rate
+ */
+pub fn rdtsc() -> u64 {
+  unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+// time stamp counter cycles per millisecond, 0 until calibrated
+static TSC_PER_MS: AtomicU64 = AtomicU64::new(0);
+// time stamp counter at the tick calibration starts on
+static CALIBRATION_START_TSC: AtomicU64 = AtomicU64::new(0);
+
+// the timer counts cycles from the first tick, a tick boundary, over a couple of whole ticks
+const CALIBRATION_FIRST_TICK: u64 = 1;
+const CALIBRATION_TICKS: u64 = 2;
+
+/**
+ * tsc_per_ms returns how many time stamp counter cycles make up a millisecond
+ * None until the timer has calibrated it, CALIBRATION_TICKS after its first tick (~165 ms)
+ */
+pub fn tsc_per_ms() -> Option<u64> {
+  match TSC_PER_MS.load(Ordering::Relaxed) {
+    0 => None,
+    per_ms => Some(per_ms),
+  }
+}
+
+/**
+ * calibrate_tsc times ticks the timer counts anyway against the time stamp counter,
+ * so nothing has to wait for the calibration
+ */
+fn calibrate_tsc(ticks: u64) {
+  if ticks == CALIBRATION_FIRST_TICK {
+    CALIBRATION_START_TSC.store(rdtsc(), Ordering::Relaxed);
+  } else if ticks == CALIBRATION_FIRST_TICK + CALIBRATION_TICKS {
+    let cycles = rdtsc().saturating_sub(CALIBRATION_START_TSC.load(Ordering::Relaxed));
+    TSC_PER_MS.store(cycles_per_ms(cycles, CALIBRATION_TICKS), Ordering::Relaxed);
+  }
+}
+
+/**
+ * cycles_per_ms converts cycles counted over a number of ticks to cycles per millisecond
+ */
+fn cycles_per_ms(cycles: u64, ticks: u64) -> u64 {
+  // cycles / (ticks * 1000 * PIT_DIVISOR / PIT_FREQUENCY_HZ) without losing the fraction of a ms
+  (cycles * PIT_FREQUENCY_HZ / (ticks * PIT_DIVISOR * 1000)).max(1)
+}
+
+#[test_case]
+fn test_sleep_ticks() {
+  let start = ticks();
+  sleep_ticks(2);
+  assert!(ticks() >= start + 2);
+}
+
+#[test_case]
+fn test_tsc_calibrated_by_timer() {
+  sleep_ticks(CALIBRATION_FIRST_TICK + CALIBRATION_TICKS); // the test kernel's timer started at tick 0
+  assert!(tsc_per_ms().is_some());
+
+  // a counter 1000 times faster than the PIT's input clock counts PIT_FREQUENCY_HZ cycles a ms
+  assert_eq!(cycles_per_ms(2 * PIT_DIVISOR * 1000, 2), PIT_FREQUENCY_HZ);
+}
+
+#[test_case]
+fn test_ms_to_ticks() {
+  assert_eq!(ms_to_ticks(0), 0);
+  assert_eq!(ms_to_ticks(1), 1); // anything above zero sleeps for at least one tick
+  assert_eq!(ms_to_ticks(1000), 19); // 18.2 ticks per second, rounded up
+}
+
+#[test_case]
+fn test_dot_every() {
+  set_tick_indicator(TickIndicator::Dot { every: 10 });
+  let start = 1000; // any ten consecutive ticks
+  let dots = (start..start + 10).filter(|&t| dot_due(t)).count();
+  set_tick_indicator(TickIndicator::None);
+
+  assert_eq!(dots, 1);
+  assert!(!(start..start + 10).any(dot_due)); // nothing is printed without an indicator
+}
+
+#[test_case]
+fn test_dot_every_default() {
+  set_tick_indicator(TickIndicator::Dot { every: 0 });
+  let every = DOT_EVERY.load(Ordering::Relaxed);
+  set_tick_indicator(TickIndicator::None);
+
+  assert_eq!(every, DEFAULT_DOT_EVERY);
+}
@@ -0,0 +1,107 @@
+// util.rs holds small helpers that don't belong to any one subsystem.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use heapless::{consts::U32, Vec};
+
+// the most frames a backtrace can hold
+pub type MaxFrames = U32;
+
+// the highest stack address backtrace will read, 0 until record_stack_top runs
+static STACK_TOP: AtomicU64 = AtomicU64::new(0);
+
+/**
+ * record_stack_top remembers the caller's frame as the outermost one a backtrace walks to
+ * call it first thing in the kernel's entry point, only the first call has an effect
+ */
+#[inline(never)]
+pub fn record_stack_top() {
+  let rbp = frame_pointer();
+  // our own frame isn't interesting, the caller's frame starts at the rbp it saved
+  let caller_rbp = unsafe { *(rbp as *const u64) };
+  let frame_base = if caller_rbp > rbp { caller_rbp } else { rbp };
+  // leave room for the saved rbp and return address at the base of that frame
+  let _ = STACK_TOP.compare_exchange(0, frame_base + 16, Ordering::Relaxed, Ordering::Relaxed);
+}
+
+/**
+ * backtrace follows the chain of saved rbp values and collects up to max_frames return addresses
+ * the innermost frame comes first; the addresses can be symbolized offline against the kernel binary
+ * this relies on the kernel being built with frame pointers (see .cargo/config.toml)
+ * and returns nothing if record_stack_top hasn't been called
+ */
+#[inline(never)]
+pub fn backtrace(max_frames: usize) -> Vec<u64, MaxFrames> {
+  let mut frames = Vec::new();
+  let top = STACK_TOP.load(Ordering::Relaxed);
+  let mut rbp = frame_pointer();
+  let bottom = rbp;
+
+  while frames.len() < max_frames {
+    // only read frames that are aligned and lie between here and the top of the stack
+    if top == 0 || rbp % 8 != 0 || rbp < bottom || rbp + 16 > top {
+      break;
+    }
+    let return_address = unsafe { *((rbp + 8) as *const u64) };
+    if return_address == 0 || frames.push(return_address).is_err() {
+      break;
+    }
+
+    // every caller's frame is further up the stack, anything else means the chain is broken
+    let next = unsafe { *(rbp as *const u64) };
+    if next <= rbp {
+      break;
+    }
+    rbp = next;
+  }
+  frames
+}
+
+/**
+ * frame_pointer reads rbp, the base of the current stack frame
+ */
+#[inline(always)]
+fn frame_pointer() -> u64 {
+  let rbp: u64;
+  unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+  rbp
+}
+
+// a known call depth to take a backtrace from
+// the volatile reads after each call keep the calls from becoming tail calls
+#[cfg(test)]
+#[inline(never)]
+fn backtrace_depth_1() -> Vec<u64, MaxFrames> {
+  let frames = backtrace_depth_2();
+  volatile::Volatile::new(0).read();
+  frames
+}
+
+#[cfg(test)]
+#[inline(never)]
+fn backtrace_depth_2() -> Vec<u64, MaxFrames> {
+  let frames = backtrace_depth_3();
+  volatile::Volatile::new(0).read();
+  frames
+}
+
+#[cfg(test)]
+#[inline(never)]
+fn backtrace_depth_3() -> Vec<u64, MaxFrames> {
+  let frames = backtrace(16);
+  volatile::Volatile::new(0).read();
+  frames
+}
+
+#[test_case]
+fn test_backtrace() {
+  use crate::memory::is_canonical;
+
+  let frames = backtrace_depth_1();
+  assert!(frames.len() >= 3);
+  for &address in frames.iter() {
+    assert!(address != 0 && is_canonical(address));
+  }
+
+  // every frame returns somewhere different
+  assert!(frames[0] != frames[1] && frames[1] != frames[2]);
+}
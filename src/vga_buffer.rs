@@ -1,5 +1,9 @@
+use alloc::string::String;
 use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+#[cfg(not(feature = "headless"))]
 use lazy_static::lazy_static;
+#[cfg(not(feature = "headless"))]
 use spin::Mutex;
 use volatile::Volatile;
 
@@ -26,18 +30,22 @@ pub enum Color {
   White = 15,
 }
 
-// ColorCode is a tuple struct representing a Color
+// ColorCode is a tuple struct representing a foreground and background Color
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)] // ensures that ColorCode has the same data layout as u8
-struct ColorCode(u8);
+pub struct ColorCode(u8);
 
 impl ColorCode {
-  fn new(foreground: Color, background: Color) -> ColorCode {
+  pub fn new(foreground: Color, background: Color) -> ColorCode {
     // create a byte with the bg as the first 4 bits and fg as the last 4
     ColorCode((background as u8) << 4 | (foreground as u8))
   }
 }
 
+// the color everything is printed in unless changed
+pub const DEFAULT_FOREGROUND: Color = Color::Yellow;
+pub const DEFAULT_BACKGROUND: Color = Color::Black;
+
 // ScreenChar is a struct representing a character and its color on screen
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(C)] // do what C does
@@ -47,8 +55,8 @@ struct ScreenChar {
 }
 
 // screen is 80x25 spaces
-const BUFFER_WIDTH: usize = 80;
-const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_WIDTH: usize = 80;
+pub const BUFFER_HEIGHT: usize = 25;
 
 // Buffer represents the VGA screenspace
 #[repr(transparent)]
@@ -58,10 +66,51 @@ struct Buffer {
   chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
+// clear_screen and new_line move whole rows around and must never be re-entered (e.g. by a
+// handler printing in the middle of a scroll), or rows get read and written half shifted.
+// debug builds set this flag while either one runs and count any re-entry
+static SCROLL_IN_PROGRESS: AtomicBool = AtomicBool::new(false);
+static SCROLL_REENTRIES: AtomicUsize = AtomicUsize::new(0);
+
+// ScrollGuard marks a scroll in progress for as long as it lives
+struct ScrollGuard {
+  entered: bool, // only the outermost guard clears the flag
+}
+
+impl ScrollGuard {
+  /**
+   * enter a scroll, warning on serial if one is already in progress
+   * serial is used because the VGA writer is the thing being re-entered
+   */
+  fn enter() -> ScrollGuard {
+    if !cfg!(debug_assertions) {
+      return ScrollGuard { entered: false };
+    }
+    let already_scrolling = SCROLL_IN_PROGRESS.swap(true, Ordering::SeqCst);
+    if already_scrolling {
+      SCROLL_REENTRIES.fetch_add(1, Ordering::SeqCst);
+      crate::serial_println!("WARNING: vga_buffer clear_screen/new_line re-entered");
+    }
+    ScrollGuard {
+      entered: !already_scrolling,
+    }
+  }
+}
+
+impl Drop for ScrollGuard {
+  fn drop(&mut self) {
+    if self.entered {
+      SCROLL_IN_PROGRESS.store(false, Ordering::SeqCst);
+    }
+  }
+}
+
 // Writer keeps track of the cursor and a reference to the screen buffer
 pub struct Writer {
   column_position: usize,
   color_code: ColorCode,
+  max_string_len: Option<usize>, // strings longer than this are cut off with "..."
+  wrap_indent: usize,            // column a line continues at when it wraps at the right edge
   buffer: &'static mut Buffer,
 }
 
@@ -70,12 +119,22 @@ impl Writer {
    * write a byte to VGA address space
    */
   pub fn write_byte(&mut self, byte: u8) {
+    self.put_byte(byte);
+    self.update_cursor();
+  }
+
+  /**
+   * write a byte to VGA address space without moving the hardware cursor
+   */
+  fn put_byte(&mut self, byte: u8) {
     match byte {
       b'\n' => self.new_line(), // if the byte is a newline, create a new line
       byte => {
         // if the column is at the end of the screen, create a new line
+        // and continue at the wrap indent, explicit newlines always go back to column 0
         if self.column_position >= BUFFER_WIDTH {
           self.new_line();
+          self.column_position = self.wrap_indent;
         }
 
         let row = BUFFER_HEIGHT - 1; // the bottom row
@@ -96,18 +155,169 @@ impl Writer {
    * write a string to the screen
    */
   pub fn write_string(&mut self, s: &str) {
+    let mut written = 0;
     for byte in s.bytes() {
+      // NULs (e.g. from C strings) are skipped rather than shown as squares
+      if byte == 0 {
+        continue;
+      }
+      // stop at the length limit so the writer lock isn't held for too long
+      if self.max_string_len == Some(written) {
+        for &dot in b"..." {
+          self.put_byte(dot);
+        }
+        break;
+      }
+
       match byte {
-        0x20..=0x7e | b'\n' => self.write_byte(byte), // printable ascii
-        _ => self.write_byte(0xfe),                   // not printable, print a square
+        0x20..=0x7e | b'\n' => self.put_byte(byte), // printable ascii
+        _ => self.put_byte(0xfe),                   // not printable, print a square
       }
+      written += 1;
     }
+    self.update_cursor();
+  }
+
+  /**
+   * limit how many characters a single write_string call prints, None means no limit
+   * note that print! calls write_string once per formatted piece, not once per call
+   */
+  pub fn set_max_string_len(&mut self, max_len: Option<usize>) {
+    self.max_string_len = max_len;
+  }
+
+  /**
+   * set the column wrapped lines continue at, so long lines stay readable
+   * kept below the screen width so every wrapped line still has room for a character
+   */
+  pub fn set_wrap_indent(&mut self, cols: usize) {
+    self.wrap_indent = cols.min(BUFFER_WIDTH - 1);
+  }
+
+  /**
+   * set the colors used for everything written from now on
+   */
+  pub fn set_color(&mut self, foreground: Color, background: Color) {
+    self.color_code = ColorCode::new(foreground, background);
+  }
+
+  /**
+   * get the colors currently used for writing
+   */
+  pub fn color_code(&self) -> ColorCode {
+    self.color_code
+  }
+
+  /**
+   * get the current colors as the raw attribute byte (background in the high 4 bits)
+   */
+  pub fn color_byte(&self) -> u8 {
+    self.color_code.0
+  }
+
+  /**
+   * restore colors from a raw attribute byte returned by color_byte
+   */
+  pub fn set_color_byte(&mut self, byte: u8) {
+    self.color_code = ColorCode(byte);
+  }
+
+  /**
+   * move the blinking hardware cursor to where the next character will be written
+   * the VGA CRT controller takes the cursor position as a cell index through registers 0x0E and 0x0F
+   */
+  fn update_cursor(&mut self) {
+    use x86_64::instructions::port::Port;
+
+    let col = self.column_position.min(BUFFER_WIDTH - 1);
+    let position = ((BUFFER_HEIGHT - 1) * BUFFER_WIDTH + col) as u16;
+    let mut index: Port<u8> = Port::new(0x3d4);
+    let mut data: Port<u8> = Port::new(0x3d5);
+    unsafe {
+      index.write(0x0f); // cursor location low byte
+      data.write((position & 0xff) as u8);
+      index.write(0x0e); // cursor location high byte
+      data.write((position >> 8) as u8);
+    }
+  }
+
+  /**
+   * render the visible screen as text, one line per row with trailing spaces trimmed
+   */
+  pub fn screen_to_string(&self) -> String {
+    let mut screen = String::with_capacity(BUFFER_HEIGHT * (BUFFER_WIDTH + 1));
+    for row in 0..BUFFER_HEIGHT {
+      let line_start = screen.len();
+      for col in 0..BUFFER_WIDTH {
+        let character = self.buffer.chars[row][col].read();
+        screen.push(char::from(character.ascii_character));
+      }
+      // drop the trailing spaces of this row
+      let trimmed_len = line_start + screen[line_start..].trim_end_matches(' ').len();
+      screen.truncate(trimmed_len);
+      if row < BUFFER_HEIGHT - 1 {
+        screen.push('\n');
+      }
+    }
+    screen
+  }
+
+  /**
+   * write bytes at a fixed position without moving the cursor, anything off screen is cut off
+   */
+  pub fn write_at(&mut self, row: usize, col: usize, bytes: &[u8]) {
+    if row >= BUFFER_HEIGHT {
+      return;
+    }
+    for (i, &byte) in bytes.iter().enumerate().take(BUFFER_WIDTH.saturating_sub(col)) {
+      self.buffer.chars[row][col + i].write(ScreenChar {
+        ascii_character: byte,
+        color_code: self.color_code,
+      });
+    }
+  }
+
+  /**
+   * write text at double size without moving the cursor, e.g. for a banner
+   * each character takes two columns and two rows: the glyph doubled on the top row and
+   * CP437 upper half blocks under it, which stretch it downwards. anything off screen is cut off
+   */
+  pub fn draw_big_text(&mut self, row: usize, col: usize, s: &str) {
+    const UPPER_HALF_BLOCK: u8 = 0xdf;
+
+    for (i, byte) in s.bytes().enumerate() {
+      let (glyph, stretch) = match byte {
+        b' ' => (b' ', b' '),
+        0x21..=0x7e => (byte, UPPER_HALF_BLOCK),
+        _ => (0xfe, UPPER_HALF_BLOCK), // not printable, draw a square
+      };
+      let glyph_col = col + 2 * i;
+      self.write_at(row, glyph_col, &[glyph, glyph]);
+      self.write_at(row.saturating_add(1), glyph_col, &[stretch, stretch]);
+    }
+  }
+
+  /**
+   * erase the character before the cursor and move back onto it
+   */
+  pub fn backspace(&mut self) {
+    if self.column_position == 0 {
+      return;
+    }
+    self.column_position -= 1;
+    self.buffer.chars[BUFFER_HEIGHT - 1][self.column_position].write(ScreenChar {
+      ascii_character: b' ',
+      color_code: self.color_code,
+    });
+    self.update_cursor();
   }
 
   /**
    * overwrite the entire screen with spaces
+   * must not be re-entered, see SCROLL_IN_PROGRESS
    */
   pub fn clear_screen(&mut self) {
+    let _guard = ScrollGuard::enter();
     for row in 0..BUFFER_HEIGHT {
       self.clear_row(row);
     }
@@ -115,8 +325,10 @@ impl Writer {
 
   /**
    * create a new line, pushing all other lines up
+   * must not be re-entered, see SCROLL_IN_PROGRESS
    */
   fn new_line(&mut self) {
+    let _guard = ScrollGuard::enter();
     for row in 1..BUFFER_HEIGHT {
       for col in 0..BUFFER_WIDTH {
         let character = self.buffer.chars[row][col].read();
@@ -151,11 +363,15 @@ impl fmt::Write for Writer {
 
 // create a lazily initialized static writer
 // this is necessary because references to pointers cannot be determined at compile-time
+// headless builds have no WRITER, so nothing ever touches 0xb8000
+#[cfg(not(feature = "headless"))]
 lazy_static! {
   // the use of spin Mutex allows safe access to the writer without the concept of threads
   pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
     column_position: 0,
-    color_code: ColorCode::new(Color::Yellow, Color::Black),
+    color_code: ColorCode::new(DEFAULT_FOREGROUND, DEFAULT_BACKGROUND),
+    max_string_len: None,
+    wrap_indent: 0,
     buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
   });
 }
@@ -173,6 +389,15 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+// print a line in the given colors, then go back to the colors that were in use
+// e.g. colored_println!(Color::Red, Color::Black, "error: {}", reason)
+#[macro_export]
+macro_rules! colored_println {
+    ($fg:expr, $bg:expr, $($arg:tt)*) => (
+      $crate::vga_buffer::_print_colored($fg, $bg, format_args!("{}\n", format_args!($($arg)*)))
+    );
+}
+
 #[macro_export]
 macro_rules! clear_screen {
   () => {
@@ -181,6 +406,7 @@ macro_rules! clear_screen {
 }
 
 #[doc(hidden)]
+#[cfg(not(feature = "headless"))]
 pub fn _print(args: fmt::Arguments) {
   use core::fmt::Write;
   use x86_64::instructions::interrupts;
@@ -191,7 +417,116 @@ pub fn _print(args: fmt::Arguments) {
   });
 }
 
+/**
+ * print_block prints each line followed by a newline while holding the writer lock throughout,
+ * so output from other code (including interrupt handlers) can't end up between the lines
+ */
+#[cfg(not(feature = "headless"))]
+pub fn print_block(lines: &[&str]) {
+  use x86_64::instructions::interrupts;
+
+  interrupts::without_interrupts(|| {
+    let mut writer = WRITER.lock();
+    for line in lines {
+      writer.write_string(line);
+      writer.write_byte(b'\n');
+    }
+  });
+}
+
+#[cfg(feature = "headless")]
+pub fn print_block(lines: &[&str]) {
+  crate::serial::print_block(lines);
+}
+
+/**
+ * write_at puts text at a fixed position on screen, e.g. a status line, without moving the cursor
+ * safe to call from an interrupt handler, it gives up rather than wait if the writer is busy
+ */
+#[cfg(not(feature = "headless"))]
+pub fn write_at(row: usize, col: usize, bytes: &[u8]) {
+  use x86_64::instructions::interrupts;
+
+  interrupts::without_interrupts(|| {
+    if let Some(mut writer) = WRITER.try_lock() {
+      writer.write_at(row, col, bytes);
+    }
+  });
+}
+
+// there is no screen to draw on when headless
+#[cfg(feature = "headless")]
+pub fn write_at(_row: usize, _col: usize, _bytes: &[u8]) {}
+
+/**
+ * draw_big_text writes double size text at a fixed position without moving the cursor
+ * like write_at it gives up rather than wait if the writer is busy
+ */
+#[cfg(not(feature = "headless"))]
+pub fn draw_big_text(row: usize, col: usize, s: &str) {
+  use x86_64::instructions::interrupts;
+
+  interrupts::without_interrupts(|| {
+    if let Some(mut writer) = WRITER.try_lock() {
+      writer.draw_big_text(row, col, s);
+    }
+  });
+}
+
+// there is no screen to draw on when headless
+#[cfg(feature = "headless")]
+pub fn draw_big_text(_row: usize, _col: usize, _s: &str) {}
+
+#[doc(hidden)]
+#[cfg(not(feature = "headless"))]
+pub fn _print_colored(foreground: Color, background: Color, args: fmt::Arguments) {
+  use core::fmt::Write;
+  use x86_64::instructions::interrupts;
+
+  // hold the lock for the whole write so nothing else is printed in these colors
+  interrupts::without_interrupts(|| {
+    let mut writer = WRITER.lock();
+    let previous = writer.color_code;
+    writer.set_color(foreground, background);
+    writer.write_fmt(args).unwrap();
+    writer.color_code = previous;
+  });
+}
+
 #[doc(hidden)]
+#[cfg(feature = "headless")]
+pub fn _print_colored(_foreground: Color, _background: Color, args: fmt::Arguments) {
+  crate::serial::_print(args);
+}
+
+// with the headless feature print! and println! go straight to the serial port
+// and the VGA buffer at 0xb8000 is never touched, since it may not exist
+#[doc(hidden)]
+#[cfg(feature = "headless")]
+pub fn _print(args: fmt::Arguments) {
+  crate::serial::_print(args);
+}
+
+/**
+ * backspace erases the last character on the current line
+ */
+#[cfg(not(feature = "headless"))]
+pub fn backspace() {
+  use x86_64::instructions::interrupts;
+
+  interrupts::without_interrupts(|| {
+    WRITER.lock().backspace();
+  });
+}
+
+// a serial terminal erases a character with backspace, space, backspace
+#[cfg(feature = "headless")]
+pub fn backspace() {
+  crate::serial_print!("\u{8} \u{8}");
+}
+
+#[doc(hidden)]
+#[cfg(not(feature = "headless"))]
 pub fn _clear_screen() {
   use x86_64::instructions::interrupts;
 
@@ -200,6 +535,21 @@ pub fn _clear_screen() {
   });
 }
 
+// there is no screen to clear when headless
+#[doc(hidden)]
+#[cfg(feature = "headless")]
+pub fn _clear_screen() {}
+
+/**
+ * screen_to_string renders the whole visible screen as text, useful for snapshot tests
+ */
+#[cfg(not(feature = "headless"))]
+pub fn screen_to_string() -> String {
+  use x86_64::instructions::interrupts;
+
+  interrupts::without_interrupts(|| WRITER.lock().screen_to_string())
+}
+
 #[test_case]
 fn test_println_simple() {
   println!("test println simple");
@@ -232,3 +582,198 @@ fn test_println_many() {
 fn test_clear_screen() {
   clear_screen!();
 }
+
+#[test_case]
+#[cfg(not(feature = "headless"))]
+fn test_screen_to_string() {
+  use core::fmt::Write;
+  use x86_64::instructions::interrupts;
+
+  // keep the timer from printing between the lines and the snapshot
+  interrupts::without_interrupts(|| {
+    let mut writer = WRITER.lock();
+    writeln!(writer, "\nfirst line").expect("writeln failed");
+    writeln!(writer, "second line").expect("writeln failed");
+
+    let screen = writer.screen_to_string();
+    let rows: alloc::vec::Vec<&str> = screen.split('\n').collect();
+    assert_eq!(rows.len(), BUFFER_HEIGHT);
+    assert_eq!(rows[BUFFER_HEIGHT - 3], "first line");
+    assert_eq!(rows[BUFFER_HEIGHT - 2], "second line");
+    assert_eq!(rows[BUFFER_HEIGHT - 1], "");
+  });
+}
+
+#[test_case]
+#[cfg(not(feature = "headless"))]
+fn test_set_color() {
+  use x86_64::instructions::interrupts;
+
+  interrupts::without_interrupts(|| {
+    let mut writer = WRITER.lock();
+    writer.write_byte(b'\n');
+    writer.set_color(Color::Red, Color::Blue);
+    writer.write_byte(b'x');
+
+    let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 1][0].read();
+    assert_eq!(screen_char.ascii_character, b'x');
+    assert_eq!(screen_char.color_code, ColorCode::new(Color::Red, Color::Blue));
+    assert_eq!(writer.color_code(), ColorCode::new(Color::Red, Color::Blue));
+
+    writer.set_color(DEFAULT_FOREGROUND, DEFAULT_BACKGROUND);
+  });
+}
+
+#[test_case]
+#[cfg(not(feature = "headless"))]
+fn test_write_string_skips_nul() {
+  use x86_64::instructions::interrupts;
+
+  interrupts::without_interrupts(|| {
+    let mut writer = WRITER.lock();
+    writer.write_string("\nab\0cd");
+
+    let row = &writer.buffer.chars[BUFFER_HEIGHT - 1];
+    for (i, &expected) in b"abcd ".iter().enumerate() {
+      assert_eq!(row[i].read().ascii_character, expected);
+    }
+  });
+}
+
+#[test_case]
+#[cfg(not(feature = "headless"))]
+fn test_write_string_max_len() {
+  use x86_64::instructions::interrupts;
+
+  interrupts::without_interrupts(|| {
+    let mut writer = WRITER.lock();
+    writer.write_byte(b'\n');
+    writer.set_max_string_len(Some(4));
+    writer.write_string("abcdefgh");
+    writer.set_max_string_len(None);
+
+    let row = &writer.buffer.chars[BUFFER_HEIGHT - 1];
+    for (i, &expected) in b"abcd... ".iter().enumerate() {
+      assert_eq!(row[i].read().ascii_character, expected);
+    }
+  });
+}
+
+#[test_case]
+#[cfg(not(feature = "headless"))] // headless, the blocks and the dots both go to serial
+fn test_print_block() {
+  use crate::time::{self, TickIndicator};
+
+  const BLOCK: [&str; 3] = ["block line 1", "block line 2", "block line 3"];
+
+  // the timer prints a dot on every tick, a second producer racing the blocks
+  // keep printing blocks until a couple of ticks have gone by, so the last dot is on screen
+  time::set_tick_indicator(TickIndicator::Dot { every: 1 });
+  let start = time::ticks();
+  while time::ticks() < start + 2 {
+    print_block(&BLOCK);
+  }
+  time::set_tick_indicator(TickIndicator::None);
+
+  let screen = screen_to_string();
+  assert!(screen.contains('.'));
+  // dots only ever land between blocks, i.e. in front of a block's first line
+  let rows: alloc::vec::Vec<&str> = screen.split('\n').collect();
+  for pair in rows[..BUFFER_HEIGHT - 1].windows(2) {
+    let line = pair[1];
+    if line == BLOCK[1] || line == BLOCK[2] {
+      let previous = if line == BLOCK[1] { BLOCK[0] } else { BLOCK[1] };
+      assert_eq!(pair[0].trim_start_matches('.'), previous);
+    } else {
+      assert_eq!(line.trim_start_matches('.'), BLOCK[0]);
+    }
+  }
+}
+
+#[test_case]
+#[cfg(not(feature = "headless"))]
+fn test_color_byte_round_trip() {
+  use x86_64::instructions::interrupts;
+
+  interrupts::without_interrupts(|| {
+    let mut writer = WRITER.lock();
+    let original = writer.color_code();
+    let saved = writer.color_byte();
+
+    writer.set_color(Color::White, Color::Magenta);
+    assert_eq!(writer.color_byte(), 0x5f);
+    writer.set_color_byte(saved);
+    assert_eq!(writer.color_code(), original);
+  });
+}
+
+#[test_case]
+#[cfg(not(feature = "headless"))]
+fn test_scroll_guard_not_tripped() {
+  use x86_64::instructions::interrupts;
+
+  let reentries = SCROLL_REENTRIES.load(Ordering::SeqCst);
+  interrupts::without_interrupts(|| {
+    let mut writer = WRITER.lock();
+    writer.write_string("\nscroll\nscroll\n");
+    writer.clear_screen();
+  });
+  assert!(!SCROLL_IN_PROGRESS.load(Ordering::SeqCst));
+  assert_eq!(SCROLL_REENTRIES.load(Ordering::SeqCst), reentries);
+}
+
+#[test_case]
+#[cfg(not(feature = "headless"))]
+fn test_wrap_indent() {
+  use x86_64::instructions::interrupts;
+
+  interrupts::without_interrupts(|| {
+    let mut writer = WRITER.lock();
+    writer.set_wrap_indent(4);
+    writer.write_byte(b'\n');
+    for _ in 0..BUFFER_WIDTH {
+      writer.write_byte(b'a');
+    }
+    writer.write_string("bc\nd");
+    writer.set_wrap_indent(0);
+
+    // the wrapped line continues at column 4, the explicit newline goes back to 0
+    let wrapped = &writer.buffer.chars[BUFFER_HEIGHT - 2];
+    for (i, &expected) in b"    bc ".iter().enumerate() {
+      assert_eq!(wrapped[i].read().ascii_character, expected);
+    }
+    assert_eq!(writer.buffer.chars[BUFFER_HEIGHT - 1][0].read().ascii_character, b'd');
+  });
+}
+
+#[test_case]
+#[cfg(not(feature = "headless"))]
+fn test_write_at_off_screen() {
+  use x86_64::instructions::interrupts;
+
+  interrupts::without_interrupts(|| {
+    let mut writer = WRITER.lock();
+    let before = writer.screen_to_string();
+    writer.write_at(BUFFER_HEIGHT, 0, b"below");
+    writer.write_at(0, BUFFER_WIDTH, b"right");
+    assert_eq!(writer.screen_to_string(), before);
+  });
+}
+
+#[test_case]
+#[cfg(not(feature = "headless"))]
+fn test_draw_big_text() {
+  use x86_64::instructions::interrupts;
+
+  interrupts::without_interrupts(|| {
+    let mut writer = WRITER.lock();
+    writer.draw_big_text(2, 10, "A");
+
+    let top = &writer.buffer.chars[2];
+    let bottom = &writer.buffer.chars[3];
+    for col in 10..12 {
+      assert_eq!(top[col].read().ascii_character, b'A');
+      assert_eq!(bottom[col].read().ascii_character, 0xdf);
+    }
+  });
+}
@@ -0,0 +1,73 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(cloudos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use cloudos::boot::{self, MEMORY};
+use cloudos::elf;
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+  // the full boot sequence, the tests below share the mapper and frame allocator it keeps in MEMORY
+  boot::run(boot_info).expect("boot failed");
+
+  test_main();
+  loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+  cloudos::test_panic_handler(info)
+}
+
+// where the test program is linked, an otherwise unused part of the address space
+const LOAD_ADDRESS: u64 = 0x_6666_0000_0000;
+
+// the program's code: nop; nop; nop; ret
+const CODE: [u8; 4] = [0x90, 0x90, 0x90, 0xc3];
+
+// a position-dependent program with a single read + execute PT_LOAD segment holding CODE
+// followed by 0x20 bytes of zero-initialized memory
+const IMAGE: [u8; 124] = [
+  // file header
+  0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+  0x02, 0x00, 0x3e, 0x00, 0x01, 0x00, 0x00, 0x00,
+  0x00, 0x00, 0x00, 0x00, 0x66, 0x66, 0x00, 0x00, // e_entry: LOAD_ADDRESS
+  0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // e_phoff: 64
+  0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // e_shoff
+  0x00, 0x00, 0x00, 0x00, 0x40, 0x00, 0x38, 0x00, // e_flags, e_ehsize, e_phentsize
+  0x01, 0x00, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, // e_phnum, e_shentsize, e_shnum, e_shstrndx
+  // program header
+  0x01, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, // PT_LOAD, R + X
+  0x78, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // p_offset: 120
+  0x00, 0x00, 0x00, 0x00, 0x66, 0x66, 0x00, 0x00, // p_vaddr: LOAD_ADDRESS
+  0x00, 0x00, 0x00, 0x00, 0x66, 0x66, 0x00, 0x00, // p_paddr
+  0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // p_filesz: 4
+  0x24, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // p_memsz: 0x24
+  0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // p_align
+  // code
+  0x90, 0x90, 0x90, 0xc3,
+];
+
+#[test_case]
+fn load_places_code_at_vaddr() {
+  let mut guard = MEMORY.lock();
+  let memory = guard.as_mut().unwrap();
+
+  let entry = elf::load(
+    &IMAGE,
+    &mut memory.mapper,
+    &mut memory.frame_allocator,
+    memory.phys_mem_offset,
+  )
+  .expect("loading failed");
+  assert_eq!(entry.as_u64(), LOAD_ADDRESS);
+
+  let loaded = unsafe { core::slice::from_raw_parts(LOAD_ADDRESS as *const u8, 0x24) };
+  assert_eq!(&loaded[..4], &CODE);
+  assert!(loaded[4..].iter().all(|&b| b == 0)); // the bss tail is zeroed
+}
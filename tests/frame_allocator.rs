@@ -0,0 +1,61 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(cloudos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use cloudos::boot::{self, MEMORY};
+use cloudos::memory;
+use core::panic::PanicInfo;
+use x86_64::structures::paging::FrameAllocator;
+use x86_64::PhysAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+  // the full boot sequence, the tests below use the frame allocator it keeps in MEMORY
+  boot::run(boot_info).expect("boot failed");
+
+  test_main();
+  loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+  cloudos::test_panic_handler(info)
+}
+
+#[test_case]
+fn frames_are_increasing_and_disjoint() {
+  let mut guard = MEMORY.lock();
+  let frame_allocator = &mut guard.as_mut().unwrap().frame_allocator;
+
+  let mut previous = frame_allocator.allocate_frame().unwrap();
+  for _ in 0..1000 {
+    let frame = frame_allocator.allocate_frame().unwrap();
+    // each frame starts at or after the end of the one before it
+    assert!(frame.start_address().as_u64() >= previous.start_address().as_u64() + 4096);
+    previous = frame;
+  }
+}
+
+#[test_case]
+fn allocate_frame_in_range_stays_below_cap() {
+  const CAP: u64 = 16 * 1024 * 1024; // 16 MiB
+  let mut guard = MEMORY.lock();
+  let frame_allocator = &mut guard.as_mut().unwrap().frame_allocator;
+
+  // drain every frame below the cap
+  let mut count = 0;
+  while let Some(frame) = frame_allocator.allocate_frame_in_range(PhysAddr::new(CAP)) {
+    assert!(frame.start_address().as_u64() + 4096 <= CAP);
+    // the first 1 MiB is reserved for memory::map_low_memory
+    assert!(frame.start_address().as_u64() >= memory::LOW_MEMORY_SIZE);
+    count += 1;
+  }
+  assert!(count > 0);
+
+  // once low memory is used up the allocator keeps failing cleanly
+  assert!(frame_allocator.allocate_frame_in_range(PhysAddr::new(CAP)).is_none());
+}
@@ -0,0 +1,45 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(cloudos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use cloudos::{println, serial};
+use core::panic::PanicInfo;
+
+#[no_mangle] // don't mangle the name of this function
+pub extern "C" fn _start() -> ! {
+  test_main();
+
+  loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+  cloudos::test_panic_handler(info);
+}
+
+// read the raw VGA text buffer (80x25 cells of 2 bytes) without going through the writer
+fn vga_snapshot() -> [u16; 80 * 25] {
+  let buffer = 0xb8000 as *const u16;
+  let mut cells = [0; 80 * 25];
+  for (i, cell) in cells.iter_mut().enumerate() {
+    *cell = unsafe { buffer.add(i).read_volatile() };
+  }
+  cells
+}
+
+#[test_case]
+fn println_skips_vga() {
+  let before = vga_snapshot();
+  println!("headless println output");
+  let after = vga_snapshot();
+  assert!(before[..] == after[..]);
+}
+
+#[test_case]
+fn println_reaches_serial() {
+  // short enough to fit in the UART's receive FIFO
+  let output = serial::capture_loopback(|| println!("headless"));
+  assert_eq!(&output[..], b"headless\n");
+}
@@ -8,7 +8,7 @@ extern crate alloc;
 
 use alloc::{boxed::Box, vec::Vec};
 use bootloader::{entry_point, BootInfo};
-use cloudos::allocator::HEAP_SIZE;
+use cloudos::allocator::{self, HEAP_SIZE};
 use core::panic::PanicInfo;
 
 entry_point!(main);
@@ -58,3 +58,82 @@ fn many_boxes() {
     assert_eq!(*x, i);
   }
 }
+
+#[test_case]
+fn many_boxes_long_lived() {
+  // a bump allocator can't reuse memory while long_lived is alive, so this would run out of heap
+  let long_lived = Box::new(1);
+  for i in 0..HEAP_SIZE {
+    let x = Box::new(i);
+    assert_eq!(*x, i);
+  }
+  assert_eq!(*long_lived, 1);
+}
+
+#[test_case]
+fn freed_blocks_are_reused() {
+  // interleave short and long lived allocations of a few sizes
+  let mut kept = Vec::new();
+  for i in 0..HEAP_SIZE / 16 {
+    let small = Box::new(i as u8);
+    let medium = Box::new([i; 4]);
+    if i % 64 == 0 {
+      kept.push(Box::new(i));
+    }
+    assert_eq!(*small, i as u8);
+    assert_eq!(medium[3], i);
+  }
+  assert_eq!(kept.len(), (HEAP_SIZE / 16 + 63) / 64);
+
+  // freeing a block and allocating the same size again hands back the same block
+  let first = Box::new(7u64);
+  let first_addr = &*first as *const u64;
+  drop(first);
+  let second = Box::new(8u64);
+  assert_eq!(&*second as *const u64, first_addr);
+}
+
+#[test_case]
+fn size_histogram_buckets() {
+  let before = allocator::size_histogram();
+  let small = Box::new([0u8; 8]);
+  let medium: Vec<u8> = Vec::with_capacity(20);
+  let large = Box::new([0u8; 300]);
+  let after = allocator::size_histogram();
+
+  assert_eq!(after[3] - before[3], 1); // 8 bytes
+  assert_eq!(after[5] - before[5], 1); // 20 bytes rounds up to 32
+  assert_eq!(after[9] - before[9], 1); // 300 bytes rounds up to 512
+  drop((small, medium, large));
+}
+
+#[test_case]
+fn align_histogram_buckets() {
+  use alloc::alloc::{alloc, dealloc, Layout};
+
+  let before = allocator::align_histogram();
+  let layout = Layout::from_size_align(64, 256).unwrap();
+  let ptr = unsafe { alloc(layout) };
+  assert!(!ptr.is_null());
+  assert_eq!(ptr as usize % 256, 0);
+  let after = allocator::align_histogram();
+  unsafe { dealloc(ptr, layout) };
+
+  assert_eq!(after[8] - before[8], 1); // 256 byte alignment
+}
+
+#[test_case]
+#[cfg(debug_assertions)]
+fn freed_memory_is_poisoned() {
+  use core::mem::size_of;
+
+  let block = Box::new([0x11u8; 64]);
+  let ptr = &*block as *const [u8; 64] as *const u8;
+  drop(block);
+
+  // the start of a free block holds the free list pointer, everything after it is poisoned
+  for i in size_of::<usize>()..64 {
+    let byte = unsafe { ptr.add(i).read_volatile() };
+    assert_eq!(byte, allocator::POISON_BYTE);
+  }
+}
@@ -0,0 +1,94 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(cloudos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use bootloader::{entry_point, BootInfo};
+use cloudos::allocator::{HEAP_SIZE, HEAP_START};
+use cloudos::rand::XorShift64;
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+  use cloudos::allocator;
+  use cloudos::memory::{self, BootInfoFrameAllocator};
+  use x86_64::VirtAddr;
+
+  cloudos::init();
+  let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+  let mut mapper = unsafe { memory::init(phys_mem_offset) };
+  let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+  allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+  test_main();
+  loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+  cloudos::test_panic_handler(info)
+}
+
+// fixed seed so a failure can be reproduced
+const SEED: u64 = 0x_c10d_05;
+const OPERATIONS: usize = 5000;
+// live allocations are tracked in a fixed table so the bookkeeping doesn't touch the heap
+const SLOTS: usize = 12;
+const MAX_SIZE: u64 = 2500;
+
+// a live allocation and the byte it was filled with
+#[derive(Clone, Copy)]
+struct Allocation {
+  ptr: *mut u8,
+  layout: Layout,
+  fill: u8,
+}
+
+#[test_case]
+fn random_alloc_dealloc() {
+  let mut rng = XorShift64::new(SEED);
+  let mut live: [Option<Allocation>; SLOTS] = [None; SLOTS];
+
+  for _ in 0..OPERATIONS {
+    let slot = rng.range(0, SLOTS as u64) as usize;
+    match live[slot].take() {
+      // free whatever is in the slot, after checking nothing else wrote over it
+      Some(allocation) => unsafe {
+        let bytes = core::slice::from_raw_parts(allocation.ptr, allocation.layout.size());
+        assert!(bytes.iter().all(|&b| b == allocation.fill), "allocation was overwritten");
+        dealloc(allocation.ptr, allocation.layout);
+      },
+      // or allocate a random size and alignment into it
+      None => {
+        let size = rng.range(1, MAX_SIZE) as usize;
+        let align = 1 << rng.range(0, 7); // 1 to 64 bytes
+        let layout = Layout::from_size_align(size, align).unwrap();
+        let ptr = unsafe { alloc(layout) };
+        let addr = ptr as usize;
+
+        assert!(!ptr.is_null(), "out of memory allocating {:?}", layout);
+        assert_eq!(addr % align, 0, "misaligned allocation for {:?}", layout);
+        assert!(addr >= HEAP_START && addr + size <= HEAP_START + HEAP_SIZE, "allocation outside heap");
+        for other in live.iter().flatten() {
+          let other_addr = other.ptr as usize;
+          let overlaps = addr < other_addr + other.layout.size() && other_addr < addr + size;
+          assert!(!overlaps, "allocation overlaps a live allocation");
+        }
+
+        let fill = rng.next_u64() as u8;
+        unsafe { core::ptr::write_bytes(ptr, fill, size) };
+        live[slot] = Some(Allocation { ptr, layout, fill });
+      }
+    }
+  }
+
+  // release everything that is still live
+  for allocation in live.iter().flatten() {
+    unsafe { dealloc(allocation.ptr, allocation.layout) };
+  }
+}
@@ -0,0 +1,143 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(cloudos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use cloudos::boot::{self, MEMORY};
+use cloudos::memory::{self, MemoryError};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+  // the full boot sequence, the tests below share the mapper and frame allocator it keeps in MEMORY
+  boot::run(boot_info).expect("boot failed");
+
+  test_main();
+  loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+  cloudos::test_panic_handler(info)
+}
+
+#[test_case]
+fn map_non_canonical_address() {
+  let mut guard = MEMORY.lock();
+  let memory = guard.as_mut().unwrap();
+  let frame = memory.frame_allocator.allocate_frame().unwrap();
+
+  let result = unsafe {
+    memory::create_mapping(
+      0x0000_8000_0000_0000,
+      frame,
+      PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+      &mut memory.mapper,
+      &mut memory.frame_allocator,
+    )
+  };
+  match result {
+    Err(MemoryError::InvalidAddress(addr)) => assert_eq!(addr, 0x0000_8000_0000_0000),
+    other => panic!("expected InvalidAddress, got {:?}", other),
+  }
+}
+
+#[test_case]
+fn translate_non_canonical_address() {
+  let guard = MEMORY.lock();
+  let memory = guard.as_ref().unwrap();
+
+  match memory::translate(&memory.mapper, 0xffff_7fff_ffff_f000) {
+    Err(MemoryError::InvalidAddress(_)) => {}
+    other => panic!("expected InvalidAddress, got {:?}", other),
+  }
+}
+
+#[test_case]
+fn read_bios_data_area() {
+  let mut guard = MEMORY.lock();
+  let memory = guard.as_mut().unwrap();
+
+  let low_memory = memory::map_low_memory(&mut memory.mapper, &mut memory.frame_allocator)
+    .expect("map_low_memory failed");
+  // the BDA starts with the I/O base of COM1, which is 0x3f8 on QEMU
+  let com1 = unsafe { (low_memory + 0x400u64).as_ptr::<u16>().read_volatile() };
+  assert_eq!(com1, 0x3f8);
+
+  // the mapping is read-only, so it can't be used to scribble over firmware structures
+  let snapshot = unsafe { memory::snapshot_mappings(memory.phys_mem_offset) };
+  let mapping = snapshot
+    .mappings
+    .iter()
+    .find(|mapping| mapping.virt == low_memory.as_u64())
+    .expect("low memory not mapped");
+  assert_eq!(mapping.phys, 0);
+  assert!(!mapping.flags.contains(PageTableFlags::WRITABLE));
+
+  // mapping again leaves the existing pages alone
+  assert!(memory::map_low_memory(&mut memory.mapper, &mut memory.frame_allocator).is_ok());
+}
+
+#[test_case]
+fn map_low_memory_rejects_other_mappings() {
+  let mut guard = MEMORY.lock();
+  let memory = guard.as_mut().unwrap();
+  memory::map_low_memory(&mut memory.mapper, &mut memory.frame_allocator).expect("map_low_memory failed");
+
+  // point one page of the range somewhere else
+  let page = Page::<Size4KiB>::containing_address(VirtAddr::new(memory::LOW_MEMORY_START + 0x5000));
+  memory.mapper.unmap(page).expect("unmap failed").1.flush();
+  let frame = memory.frame_allocator.allocate_frame().unwrap();
+  unsafe {
+    memory::create_mapping(
+      page.start_address().as_u64(),
+      frame,
+      PageTableFlags::PRESENT,
+      &mut memory.mapper,
+      &mut memory.frame_allocator,
+    )
+    .expect("create_mapping failed");
+  }
+  match memory::map_low_memory(&mut memory.mapper, &mut memory.frame_allocator) {
+    Err(MemoryError::MapFailed(MapToError::PageAlreadyMapped(_))) => {}
+    other => panic!("expected PageAlreadyMapped, got {:?}", other),
+  }
+
+  // once the page is gone it is mapped to the right frame again
+  memory.mapper.unmap(page).expect("unmap failed").1.flush();
+  assert!(memory::map_low_memory(&mut memory.mapper, &mut memory.frame_allocator).is_ok());
+}
+
+#[test_case]
+fn diff_shows_new_mapping() {
+  let mut guard = MEMORY.lock();
+  let memory = guard.as_mut().unwrap();
+  let addr = 0x7777_7777_0000;
+
+  let before = unsafe { memory::snapshot_mappings(memory.phys_mem_offset) };
+  let frame = memory.frame_allocator.allocate_frame().unwrap();
+  unsafe {
+    memory::create_mapping(
+      addr,
+      frame,
+      PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+      &mut memory.mapper,
+      &mut memory.frame_allocator,
+    )
+    .expect("create_mapping failed");
+  }
+  let after = unsafe { memory::snapshot_mappings(memory.phys_mem_offset) };
+
+  let diff = memory::diff(&before, &after);
+  assert_eq!(diff.added.len(), 1);
+  assert_eq!(diff.added[0].virt, addr);
+  assert_eq!(diff.added[0].phys, frame.start_address().as_u64());
+  assert!(diff.removed.is_empty());
+  assert!(diff.changed.is_empty());
+}
@@ -0,0 +1,50 @@
+#![no_std]
+#![no_main]
+
+use cloudos::interrupts::{self, PageFaultReport};
+use cloudos::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use heapless::consts::U256;
+use x86_64::structures::idt::PageFaultErrorCode;
+
+// an address that is not mapped in the kernel's address space
+const UNMAPPED_ADDRESS: u64 = 0xdeadbeef;
+
+/**
+ * check the report the kernel's page fault handler just wrote to serial
+ */
+fn check_report(report: &PageFaultReport) {
+  let mut text: heapless::String<U256> = heapless::String::new();
+  let _ = write!(text, "{}", report);
+
+  let not_present = !report.error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION);
+  if text.starts_with("EXCEPTION: PAGE FAULT") && text.contains("Accessed Address: 0xdeadbeef") && not_present {
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+  } else {
+    serial_println!("[failed]\n\nError: unexpected report\n{}", text);
+    exit_qemu(QemuExitCode::Failed);
+  }
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+  serial_print!("page_fault::read_unmapped_address...\t");
+
+  // the kernel's own GDT and IDT, so the real page fault handler runs
+  cloudos::gdt::init();
+  interrupts::init_idt();
+  interrupts::set_page_fault_hook(check_report);
+
+  // reading an unmapped address must end up in the page fault handler, not reset the machine
+  let ptr = UNMAPPED_ADDRESS as *const u8;
+  unsafe { ptr.read_volatile() };
+
+  panic!("Execution continued after page fault");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+  cloudos::test_panic_handler(info)
+}
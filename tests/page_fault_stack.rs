@@ -0,0 +1,42 @@
+#![no_std]
+#![no_main]
+
+use cloudos::interrupts::{self, PageFaultReport};
+use cloudos::{exit_qemu, gdt, serial_print, serial_println, QemuExitCode};
+use core::panic::PanicInfo;
+
+/**
+ * called by the kernel's page fault handler once it has printed its report on its IST stack
+ */
+fn check_headroom(_report: &PageFaultReport) {
+  // some of the stack was used, but the canary at the bottom was never reached
+  let headroom = gdt::page_fault_stack_headroom();
+  if headroom > 0 && headroom < gdt::PAGE_FAULT_STACK_SIZE {
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+  } else {
+    serial_println!("[failed]\n\nError: unexpected page fault stack headroom {}", headroom);
+    exit_qemu(QemuExitCode::Failed);
+  }
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+  serial_print!("page_fault_stack::page_fault_stack...\t");
+
+  // the kernel's own GDT and IDT, so the real handler runs on the real IST stack
+  gdt::init();
+  interrupts::init_idt();
+  interrupts::set_page_fault_hook(check_headroom);
+
+  // trigger a page fault by reading an unmapped address
+  let ptr = 0xdead_beef_000 as *const u64;
+  unsafe { ptr.read_volatile() };
+
+  panic!("Execution continued after page fault");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+  cloudos::test_panic_handler(info)
+}
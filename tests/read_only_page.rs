@@ -0,0 +1,73 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::format;
+use bootloader::{entry_point, BootInfo};
+use cloudos::boot::{self, MEMORY};
+use cloudos::interrupts::{self, PageFaultReport};
+use cloudos::memory;
+use cloudos::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::{FrameAllocator, PageTableFlags};
+
+// an otherwise unused page that gets mapped read-only
+const READ_ONLY_PAGE: u64 = 0x_5555_5555_0000;
+
+/**
+ * check the report the kernel's page fault handler just wrote to serial
+ */
+fn check_report(report: &PageFaultReport) {
+  let text = format!("{}", report);
+  if text.contains("attempted write to read-only page at 0x555555550000") {
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+  } else {
+    serial_println!("[failed]\n\nError: unexpected report\n{}", text);
+    exit_qemu(QemuExitCode::Failed);
+  }
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+  serial_print!("read_only_page::read_only_page...\t");
+
+  // boot::run loads the kernel's own GDT and IDT, so the real page fault handler runs,
+  // and sets up the heap for the report text and the mapper to map the page with.
+  // it must not be combined with gdt::init, loading the TSS a second time faults
+  interrupts::set_page_fault_hook(check_report);
+  boot::run(boot_info).expect("boot failed");
+
+  // map the page without the WRITABLE flag
+  {
+    let mut guard = MEMORY.lock();
+    let memory = guard.as_mut().unwrap();
+    let frame = memory.frame_allocator.allocate_frame().expect("no frames left");
+    unsafe {
+      memory::create_mapping(
+        READ_ONLY_PAGE,
+        frame,
+        PageTableFlags::PRESENT,
+        &mut memory.mapper,
+        &mut memory.frame_allocator,
+      )
+    }
+    .expect("mapping the read-only page failed");
+  }
+
+  // reading is fine, writing faults
+  let ptr = READ_ONLY_PAGE as *mut u64;
+  unsafe {
+    ptr.read_volatile();
+    ptr.write_volatile(42);
+  }
+
+  panic!("Execution continued after writing to a read-only page");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+  cloudos::test_panic_handler(info)
+}